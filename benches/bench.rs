@@ -35,6 +35,24 @@ fn bench_append_str(b: &mut Bencher) {
     });
 }
 
+#[bench]
+fn bench_extend_int(b: &mut Bencher) {
+    b.iter(move || {
+        let mut lp = listpack::Listpack::new();
+        lp.extend(0..100);
+        lp
+    });
+}
+
+#[bench]
+fn bench_extend_str(b: &mut Bencher) {
+    b.iter(move || {
+        let mut lp = listpack::Listpack::new();
+        lp.extend((0..100).map(|_| "hello there"));
+        lp
+    });
+}
+
 #[bench]
 fn bench_get_first(b: &mut Bencher) {
     let mut lp = listpack::raw::new(listpack::raw::ALLOCATOR);
@@ -55,4 +73,62 @@ fn bench_get_last(b: &mut Bencher) {
     b.iter(move || {
         get(last(lp).unwrap());
     });
-}
\ No newline at end of file
+}
+
+#[bench]
+fn bench_build_default_allocator(b: &mut Bencher) {
+    b.iter(move || {
+        let mut lp = listpack::Listpack::new_in(DefaultAllocator);
+        for i in 0..100 {
+            lp.append(i);
+        }
+        lp.append("hello there");
+        lp
+    });
+}
+
+#[bench]
+fn bench_build_arena_allocator(b: &mut Bencher) {
+    let mut arena = listpack::arena::ArenaAllocator::new();
+
+    b.iter(move || {
+        {
+            let mut lp = listpack::Listpack::new_in(&arena);
+            for i in 0..100 {
+                lp.append(i);
+            }
+            lp.append("hello there");
+            test::black_box(&lp);
+        }
+        arena.reset();
+    });
+}
+
+#[bench]
+fn bench_build_many_default_allocator(b: &mut Bencher) {
+    b.iter(move || {
+        let lps: Vec<_> = (0..16).map(|_| {
+            let mut lp = listpack::Listpack::new_in(DefaultAllocator);
+            lp.extend(0..32);
+            lp
+        }).collect();
+        lps
+    });
+}
+
+#[bench]
+fn bench_build_many_arena_allocator(b: &mut Bencher) {
+    let mut arena = listpack::arena::ArenaAllocator::new();
+
+    b.iter(move || {
+        {
+            let lps: Vec<_> = (0..16).map(|_| {
+                let mut lp = listpack::Listpack::new_in(&arena);
+                lp.extend(0..32);
+                lp
+            }).collect();
+            test::black_box(&lps);
+        }
+        arena.reset();
+    });
+}
@@ -1,12 +1,95 @@
 //extern crate cpp_build;
 extern crate gcc;
 
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// `#define`s of the `capi` header, matching the constants of src/capi.rs.
+const DEFINES: &[(&str, &str)] = &[
+    ("LP_INTBUF_SIZE", "21"),
+    ("LP_BEFORE", "0"),
+    ("LP_AFTER", "1"),
+    ("LP_REPLACE", "2"),
+];
+
+/// Functions exported by the `capi` feature, as C return type, name and
+/// arguments. Keep in sync with the `extern "C"` functions of src/capi.rs.
+const FUNCTIONS: &[(&str, &str, &[&str])] = &[
+    ("unsigned char *", "lpNew", &[]),
+    ("void ", "lpFree", &["unsigned char *lp"]),
+    ("unsigned char *", "lpInsert", &["unsigned char *lp", "unsigned char *ele", "uint32_t size",
+        "unsigned char *p", "int where", "unsigned char **newp"]),
+    ("unsigned char *", "lpAppend", &["unsigned char *lp", "unsigned char *ele", "uint32_t size"]),
+    ("unsigned char *", "lpDelete", &["unsigned char *lp", "unsigned char *p", "unsigned char **newp"]),
+    ("uint32_t ", "lpLength", &["unsigned char *lp"]),
+    ("unsigned char *", "lpGet", &["unsigned char *p", "int64_t *count", "unsigned char *intbuf"]),
+    ("unsigned char *", "lpFirst", &["unsigned char *lp"]),
+    ("unsigned char *", "lpLast", &["unsigned char *lp"]),
+    ("unsigned char *", "lpNext", &["unsigned char *lp", "unsigned char *p"]),
+    ("unsigned char *", "lpPrev", &["unsigned char *lp", "unsigned char *p"]),
+    ("uint32_t ", "lpBytes", &["unsigned char *lp"]),
+    ("unsigned char *", "lpSeek", &["unsigned char *lp", "long index"]),
+    ("unsigned char *", "lpInsertInt64", &["unsigned char *lp", "int64_t value", "unsigned char *p",
+        "int where", "unsigned char **newp"]),
+    ("unsigned char *", "lpAppendInt64", &["unsigned char *lp", "int64_t value"]),
+    ("unsigned char *", "lpReplaceInt64", &["unsigned char *lp", "unsigned char **pos", "int64_t value"]),
+];
+
+// The `gcc` crate was renamed to `cc` but still builds fine.
+#[allow(deprecated)]
 fn main() {
+    let c = env::var_os("CARGO_FEATURE_C").is_some();
+    let capi = env::var_os("CARGO_FEATURE_CAPI").is_some();
+
     // Build a Redis pseudo-library so that we have symbols that we can link
     // against while building Rust code.
-//    gcc::Build::new()
-//        .file("c/listpack.c")
-//        .file("c/listpack_ext.c")
-//        .include("c/")
-//        .compile("liblistpack.a");
+    if c {
+        println!("cargo:rerun-if-changed=c/");
+        let mut build = gcc::Build::new();
+        build
+            .file("c/listpack.c")
+            .file("c/listpack_ext.c")
+            .include("c/");
+        // The Rust implementation owns the C API names, `ffi` links the C
+        // functions under a "c_" prefix instead.
+        if capi {
+            for &(_, name, _) in FUNCTIONS {
+                build.define(name, Some(&format!("c_{}", name)[..]));
+            }
+        }
+        build.compile("liblistpack.a");
+    }
+
+    if capi {
+        println!("cargo:rerun-if-changed=build.rs");
+        let out = Path::new(&env::var("OUT_DIR").unwrap()).join("listpack.h");
+        fs::write(out, c_header()).unwrap();
+    }
+}
+
+/// Generate the C header of the `capi` module.
+fn c_header() -> String {
+    let mut header = String::from(
+        "/* Generated by build.rs, do not edit. */\n\n\
+         #ifndef __LISTPACK_H\n\
+         #define __LISTPACK_H\n\n\
+         #include <stdint.h>\n\n");
+
+    for &(name, value) in DEFINES {
+        header.push_str(&format!("#define {} {}\n", name, value));
+    }
+    header.push('\n');
+
+    for &(ret, name, args) in FUNCTIONS {
+        let args = if args.is_empty() {
+            "void".to_string()
+        } else {
+            args.join(", ")
+        };
+        header.push_str(&format!("{}{}({});\n", ret, name, args));
+    }
+
+    header.push_str("\n#endif\n");
+    header
 }
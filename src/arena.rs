@@ -0,0 +1,251 @@
+use std;
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+
+use raw::*;
+
+/// Size of the first chunk of an arena created with `ArenaAllocator::new()`.
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+
+struct Chunk {
+    ptr: *mut u8,
+    size: usize,
+}
+
+/// Bump allocator carving listpacks out of large chunks. Allocating is a
+/// pointer bump and growing the most recent allocation happens in place, so
+/// building a listpack with many appends doesn't call into the system
+/// allocator for every one of them. Freeing a listpack only gives the memory
+/// back when it is the most recent allocation, the rest is released all at
+/// once by `reset()` or when the arena is dropped.
+///
+/// Listpacks borrow the arena, e.g. `Listpack::new_in(&arena)`, so they can't
+/// outlive a reset.
+pub struct ArenaAllocator {
+    chunks: RefCell<Vec<Chunk>>,
+    /// Bytes used in the current chunk, the last one.
+    top: Cell<usize>,
+    /// Offset in the current chunk of the most recent allocation, if it
+    /// wasn't freed.
+    last: Cell<Option<usize>>,
+    chunk_size: usize,
+}
+
+impl ArenaAllocator {
+    pub fn new() -> ArenaAllocator {
+        ArenaAllocator::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Create an arena that allocates chunks of at least 'chunk_size' bytes.
+    /// No memory is allocated until the first listpack is.
+    pub fn with_chunk_size(chunk_size: usize) -> ArenaAllocator {
+        ArenaAllocator {
+            chunks: RefCell::new(Vec::new()),
+            top: Cell::new(0),
+            last: Cell::new(None),
+            chunk_size: chunk_size.max(1),
+        }
+    }
+
+    /// Total size in bytes of the chunks held by the arena.
+    pub fn capacity(&self) -> usize {
+        self.chunks.borrow().iter().map(|c| c.size).sum()
+    }
+
+    /// Bytes handed out from the current chunk.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.top.get()
+    }
+
+    /// Free every listpack allocated so far at once. The largest chunk is
+    /// kept around to serve the next allocations.
+    pub fn reset(&mut self) {
+        let chunks = self.chunks.get_mut();
+        if let Some(keep) = chunks.iter()
+            .enumerate()
+            .max_by_key(|&(_, c)| c.size)
+            .map(|(i, _)| i) {
+            let keep = chunks.swap_remove(keep);
+            for chunk in chunks.drain(..) {
+                free_chunk(chunk);
+            }
+            chunks.push(keep);
+        }
+        self.top.set(0);
+        self.last.set(None);
+    }
+
+    /// Start address and size of the current chunk.
+    #[inline]
+    fn current(&self) -> Option<(*mut u8, usize)> {
+        self.chunks.borrow().last().map(|c| (c.ptr, c.size))
+    }
+
+    fn new_chunk(&self, size: usize) -> bool {
+        let size = size.max(self.chunk_size);
+        let ptr = unsafe {
+            alloc::alloc(Layout::from_size_align_unchecked(size, 1))
+        };
+        if ptr.is_null() {
+            return false;
+        }
+        self.chunks.borrow_mut().push(Chunk { ptr, size });
+        self.top.set(0);
+        self.last.set(None);
+        true
+    }
+}
+
+impl Default for ArenaAllocator {
+    fn default() -> Self {
+        ArenaAllocator::new()
+    }
+}
+
+impl Drop for ArenaAllocator {
+    fn drop(&mut self) {
+        for chunk in self.chunks.get_mut().drain(..) {
+            free_chunk(chunk);
+        }
+    }
+}
+
+#[inline]
+fn free_chunk(chunk: Chunk) {
+    unsafe {
+        alloc::dealloc(chunk.ptr, Layout::from_size_align_unchecked(chunk.size, 1));
+    }
+}
+
+impl Allocator for ArenaAllocator {
+    #[inline(always)]
+    fn has_header(&self) -> bool {
+        true
+    }
+
+    fn alloc(&self, size: usize) -> listpack {
+        let fits = match self.current() {
+            Some((_, chunk_size)) => chunk_size - self.top.get() >= size,
+            None => false,
+        };
+        if !fits && !self.new_chunk(size) {
+            return std::ptr::null_mut();
+        }
+        let (ptr, _) = self.current().unwrap();
+        let offset = self.top.get();
+        self.top.set(offset + size);
+        self.last.set(Some(offset));
+        unsafe { ptr.add(offset) }
+    }
+
+    fn realloc(&self, lp: listpack, old_size: usize, new_size: usize) -> listpack {
+        if let (Some((ptr, chunk_size)), Some(offset)) = (self.current(), self.last.get()) {
+            if unsafe { ptr.add(offset) } == lp && chunk_size - offset >= new_size {
+                // The most recent allocation grows or shrinks in place.
+                self.top.set(offset + new_size);
+                return lp;
+            }
+        }
+        if new_size <= old_size {
+            // Any other allocation shrinks in place too, the tail is only
+            // reclaimed by a reset.
+            return lp;
+        }
+
+        let new_lp = self.alloc(new_size);
+        if !new_lp.is_null() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(lp, new_lp, old_size);
+            }
+        }
+        new_lp
+    }
+
+    fn dealloc(&self, lp: listpack, _size: usize) {
+        if let (Some((ptr, _)), Some(offset)) = (self.current(), self.last.get()) {
+            if unsafe { ptr.add(offset) } == lp {
+                self.top.set(offset);
+                self.last.set(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::Listpack;
+
+    #[test]
+    fn grows_in_place() {
+        let arena = ArenaAllocator::with_chunk_size(1024);
+        let mut lp = Listpack::new_in(&arena);
+        let ptr = lp.as_bytes().as_ptr();
+        for i in 0..100 {
+            lp.append(i);
+        }
+        assert_eq!(lp.as_bytes().as_ptr(), ptr);
+        assert_eq!(arena.used(), 6 + 100 * 2 + 1);
+        assert_eq!(arena.capacity(), 1024);
+
+        lp.delete_range(0, 50);
+        assert_eq!(arena.used(), 6 + 50 * 2 + 1);
+        drop(lp);
+        assert_eq!(arena.used(), 0);
+    }
+
+    #[test]
+    fn moves_when_not_last() {
+        let arena = ArenaAllocator::with_chunk_size(64);
+        let mut a = Listpack::new_in(&arena);
+        let mut b = Listpack::new_in(&arena);
+        a.append("first");
+        b.append("second");
+        for i in 0..50 {
+            a.append(i);
+        }
+        assert_eq!(a.get_str(first(a.0).unwrap()), "first");
+        assert_eq!(a.get_int(last(a.0).unwrap()), 49);
+        assert_eq!(b.get_str(first(b.0).unwrap()), "second");
+        assert!(arena.capacity() > 64);
+    }
+
+    #[test]
+    fn shrinks_in_place_when_not_last() {
+        let arena = ArenaAllocator::with_chunk_size(1024);
+        let mut a = Listpack::new_in(&arena);
+        a.extend(0..50);
+        let b = Listpack::new_in(&arena);
+        let ptr = a.as_bytes().as_ptr();
+        let used = arena.used();
+
+        a.delete_range(0, 40);
+        assert_eq!(a.as_bytes().as_ptr(), ptr);
+        assert_eq!(arena.used(), used);
+        assert_eq!(length(a.0), 10);
+        assert_eq!(a.get_int(first(a.0).unwrap()), 40);
+        assert!(first(b.0).is_none());
+    }
+
+    #[test]
+    fn reset() {
+        let mut arena = ArenaAllocator::with_chunk_size(32);
+        {
+            let lps: Vec<Listpack<&ArenaAllocator>> = (0..10).map(|i| {
+                let mut lp = Listpack::new_in(&arena);
+                lp.extend(0..i * 10);
+                lp
+            }).collect();
+            assert_eq!(lps[9].as_bytes().len(), 6 + 90 * 2 + 1);
+        }
+        let largest = arena.chunks.borrow().iter().map(|c| c.size).max().unwrap();
+        arena.reset();
+        assert_eq!(arena.capacity(), largest);
+        assert_eq!(arena.used(), 0);
+
+        let mut lp = Listpack::new_in(&arena);
+        lp.append("again");
+        assert_eq!(arena.capacity(), largest);
+    }
+}
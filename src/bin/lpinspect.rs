@@ -0,0 +1,853 @@
+//! Inspect serialized listpacks, for instance taken from a Redis dump.
+//!
+//! The input is read from a file or stdin and may be the raw listpack bytes,
+//! the same bytes as hex, or the payload of a Redis `DUMP` of a hash, set,
+//! sorted set, list or stream object, which holds one or more listpacks.
+//!
+//! `lpinspect build` goes the other way and makes a listpack out of a list of
+//! values, to write fixtures by hand.
+
+extern crate listpack;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use listpack::lzf;
+use listpack::raw::*;
+use listpack::Listpack;
+
+const USAGE: &str = "\
+usage: lpinspect [COMMAND] [--format auto|raw|hex|rdb] [FILE]
+       lpinspect build [--input auto|json|lines] [--output hex|raw] [FILE]
+
+Reads FILE, or stdin when FILE is missing or '-'.
+
+commands:
+    dump        print the header and every entry (default)
+    validate    check the integrity of the listpack
+    stats       print the entry count and bytes per encoding, and the
+                overhead of the header and the backlens
+    to-json     print the values as a JSON array, one line per listpack
+    build       make a listpack of the values in FILE and write it to stdout
+
+build reads a JSON array of integers and strings, or one value per line
+prefixed with its type: 'int:-12', 'str:hello' or 'hex:00ff'. Blank lines
+and lines starting with '#' are skipped. Strings are stored as strings even
+when they hold an integer.
+";
+
+// Redis object types serialized as listpacks in RDB files and DUMP payloads.
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const RDB_ENC_LZF: u8 = 3;
+const QUICKLIST_NODE_CONTAINER_PACKED: u64 = 2;
+
+const HDR: usize = HDR_SIZE as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Auto,
+    Raw,
+    Hex,
+    Rdb,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => process::exit(code),
+        Err(message) => {
+            eprintln!("lpinspect: {}", message);
+            process::exit(2);
+        }
+    }
+}
+
+/// Run the command line and return the exit status: 1 when a listpack is
+/// corrupt, 0 otherwise.
+fn run(args: &[String]) -> Result<i32, String> {
+    let mut command = "dump";
+    let mut format = Format::Auto;
+    let mut path = None;
+    // Build options: JSON input or not, when known, and hex or raw output.
+    let mut input_format = None;
+    let mut hex_output = true;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" | "help" => {
+                print!("{}", USAGE);
+                return Ok(0);
+            }
+            "-f" | "--format" => {
+                format = match args.next().map(|f| f.as_str()) {
+                    Some("auto") => Format::Auto,
+                    Some("raw") => Format::Raw,
+                    Some("hex") => Format::Hex,
+                    Some("rdb") => Format::Rdb,
+                    _ => return Err(format!("--format takes auto, raw, hex or rdb\n\n{}", USAGE)),
+                }
+            }
+            "-i" | "--input" => {
+                input_format = match args.next().map(|f| f.as_str()) {
+                    Some("auto") => None,
+                    Some("json") => Some(true),
+                    Some("lines") => Some(false),
+                    _ => return Err(format!("--input takes auto, json or lines\n\n{}", USAGE)),
+                }
+            }
+            "-o" | "--output" => {
+                hex_output = match args.next().map(|f| f.as_str()) {
+                    Some("hex") => true,
+                    Some("raw") => false,
+                    _ => return Err(format!("--output takes hex or raw\n\n{}", USAGE)),
+                }
+            }
+            "dump" | "validate" | "stats" | "to-json" | "build" if path.is_none() => command = arg,
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => return Err(format!("unexpected argument '{}'\n\n{}", arg, USAGE)),
+        }
+    }
+
+    let input = read_input(path)?;
+    if command == "build" {
+        let values = parse_values(&input, input_format)?;
+        let bytes = build(&values)?;
+        let mut stdout = io::stdout();
+        let written = if hex_output {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(stdout, "{}", hex)
+        } else {
+            stdout.write_all(&bytes)
+        };
+        written.map_err(|e| format!("stdout: {}", e))?;
+        return Ok(0);
+    }
+
+    let listpacks = decode(&input, format)?;
+
+    let mut status = 0;
+    for (i, bytes) in listpacks.iter().enumerate() {
+        if listpacks.len() > 1 && command != "to-json" {
+            if i > 0 {
+                println!();
+            }
+            println!("# listpack {} of {}", i + 1, listpacks.len());
+        }
+        let inspection = inspect(bytes);
+        match command {
+            "dump" => dump(bytes, &inspection),
+            "validate" => match inspection.error {
+                None if validate(bytes) => println!("ok: {} bytes, {} elements", bytes.len(), inspection.entries.len()),
+                None => println!("invalid listpack"),
+                Some(ref error) => println!("invalid: {}", error),
+            },
+            // Only a valid listpack can be walked without checks.
+            "stats" if inspection.error.is_none() && validate(bytes) => {
+                print_stats(&stats(bytes.as_ptr() as *mut u8))
+            }
+            "stats" => {}
+            _ => println!("{}", to_json(&inspection.entries)),
+        }
+        if inspection.error.is_some() || !validate(bytes) {
+            if command != "validate" {
+                eprintln!("lpinspect: corrupt listpack: {}",
+                          inspection.error.as_ref().map_or("invalid listpack", |e| e.as_str()));
+            }
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+fn read_input(path: Option<&str>) -> Result<Vec<u8>, String> {
+    match path {
+        None | Some("-") => {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input).map_err(|e| format!("stdin: {}", e))?;
+            Ok(input)
+        }
+        Some(path) => fs::read(path).map_err(|e| format!("{}: {}", path, e)),
+    }
+}
+
+/// Extract the listpacks out of the input. When guessing the format, input
+/// is taken as a DUMP payload only if one of its listpacks is valid, and
+/// otherwise as a corrupt listpack, so it still gets inspected.
+fn decode(input: &[u8], format: Format) -> Result<Vec<Vec<u8>>, String> {
+    match format {
+        Format::Raw => Ok(vec![input.to_vec()]),
+        Format::Hex => Ok(vec![parse_hex(input)?]),
+        Format::Rdb => parse_rdb(input),
+        Format::Auto => {
+            let bytes = if is_hex(input) {
+                parse_hex(input)?
+            } else {
+                input.to_vec()
+            };
+            if validate(&bytes) {
+                return Ok(vec![bytes]);
+            }
+            match parse_rdb(&bytes) {
+                Ok(ref listpacks) if listpacks.iter().any(|lp| validate(lp)) => Ok(listpacks.clone()),
+                _ => Ok(vec![bytes])
+            }
+        }
+    }
+}
+
+fn is_hex(input: &[u8]) -> bool {
+    let text = strip_hex_prefix(input);
+    text.iter().any(|b| !b.is_ascii_whitespace())
+        && text.iter().all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace())
+}
+
+#[inline]
+fn strip_hex_prefix(input: &[u8]) -> &[u8] {
+    let start = input.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(input.len());
+    let input = &input[start..];
+    if input.starts_with(b"0x") || input.starts_with(b"0X") {
+        &input[2..]
+    } else {
+        input
+    }
+}
+
+/// Decode hex digits, ignoring whitespace and a leading "0x".
+fn parse_hex(input: &[u8]) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = strip_hex_prefix(input)
+        .iter()
+        .cloned()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if digits.len() % 2 != 0 {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("invalid hex '{}'", String::from_utf8_lossy(pair)))
+        })
+        .collect()
+}
+
+/// Extract the listpacks of a DUMP payload: the object type, the object and
+/// the RDB version and checksum, which aren't checked.
+fn parse_rdb(input: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut reader = RdbReader { input, pos: 0 };
+    let object_type = reader.byte()?;
+    match object_type {
+        RDB_TYPE_HASH_LISTPACK | RDB_TYPE_ZSET_LISTPACK | RDB_TYPE_SET_LISTPACK => {
+            Ok(vec![reader.string()?])
+        }
+        RDB_TYPE_LIST_QUICKLIST_2 => {
+            let nodes = reader.length()?;
+            let mut listpacks = Vec::new();
+            for _ in 0..nodes {
+                let container = reader.length()?;
+                let node = reader.string()?;
+                // Plain nodes hold a single large element instead.
+                if container == QUICKLIST_NODE_CONTAINER_PACKED {
+                    listpacks.push(node);
+                }
+            }
+            Ok(listpacks)
+        }
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            let nodes = reader.length()?;
+            let mut listpacks = Vec::new();
+            for _ in 0..nodes {
+                // The master ID of the node, then the node.
+                reader.string()?;
+                listpacks.push(reader.string()?);
+            }
+            Ok(listpacks)
+        }
+        _ => Err(format!("RDB object type {} isn't stored as listpacks", object_type)),
+    }
+}
+
+struct RdbReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+/// An RDB length, or the kind of a specially encoded string.
+enum RdbLength {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<'a> RdbReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if n > self.input.len() - self.pos {
+            return Err(format!("RDB payload truncated at offset {}", self.pos));
+        }
+        let bytes = &self.input[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    #[inline]
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_length(&mut self) -> Result<RdbLength, String> {
+        let b = self.byte()?;
+        match b >> 6 {
+            0 => Ok(RdbLength::Len((b & 0x3F) as u64)),
+            1 => Ok(RdbLength::Len(((b & 0x3F) as u64) << 8 | self.byte()? as u64)),
+            2 if b == 0x80 => {
+                let be = self.take(4)?;
+                Ok(RdbLength::Len(be.iter().fold(0, |v, &b| v << 8 | b as u64)))
+            }
+            2 if b == 0x81 => {
+                let be = self.take(8)?;
+                Ok(RdbLength::Len(be.iter().fold(0, |v, &b| v << 8 | b as u64)))
+            }
+            3 => Ok(RdbLength::Encoded(b & 0x3F)),
+            _ => Err(format!("invalid RDB length at offset {}", self.pos - 1)),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, String> {
+        match self.read_length()? {
+            RdbLength::Len(len) => Ok(len),
+            RdbLength::Encoded(_) => Err(format!("expected a length at offset {}", self.pos - 1)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        match self.read_length()? {
+            RdbLength::Len(len) => Ok(self.take(len as usize)?.to_vec()),
+            RdbLength::Encoded(RDB_ENC_LZF) => {
+                let compressed = self.length()?;
+                let len = self.length()?;
+                // A listpack is at most 4GB, and the length isn't trusted.
+                if len > u32::MAX as u64 || len > lzf::max_decompressed_len(compressed as usize) as u64 {
+                    return Err(format!("invalid LZF string length {} at offset {}", len, self.pos));
+                }
+                let len = len as usize;
+                let data = self.take(compressed as usize)?;
+                lzf::decompress(data, len)
+                    .filter(|bytes| bytes.len() == len)
+                    .ok_or_else(|| "corrupt LZF compressed string".to_string())
+            }
+            RdbLength::Encoded(_) => Err(format!("integer encoded string at offset {}", self.pos - 1)),
+        }
+    }
+}
+
+struct Entry {
+    offset: usize,
+    encoding: &'static str,
+    size: usize,
+    backlen: usize,
+    value: OwnedValue,
+}
+
+/// The entries of a listpack up to the first corruption, if any.
+struct Inspection {
+    entries: Vec<Entry>,
+    error: Option<String>,
+}
+
+/// Walk the entries of 'bytes' without trusting the header or the entries,
+/// the same checks as `raw::validate()` but telling what is wrong.
+fn inspect(bytes: &[u8]) -> Inspection {
+    let mut entries = Vec::new();
+    let size = bytes.len();
+    if size < HDR + 1 {
+        return Inspection { entries, error: Some(format!("{} bytes is too short for a listpack", size)) };
+    }
+    let lp = bytes.as_ptr() as *mut u8;
+
+    let mut offset = HDR;
+    let error = loop {
+        if offset >= size {
+            break Some("no EOF byte at the end".to_string());
+        }
+        let b = bytes[offset];
+        if b == EOF {
+            break if offset != size - 1 {
+                Some(format!("EOF byte at offset {} before the end", offset))
+            } else {
+                None
+            };
+        }
+        let encoding = match Encoding::of(b) {
+            Some(encoding) => encoding.name(),
+            None => break Some(format!("invalid encoding 0x{:02x} at offset {}", b, offset)),
+        };
+
+        // Make sure the string length can be read before decoding it.
+        let len_bytes = if is_12bit_str(b) {
+            2
+        } else if is_32bit_str(b) {
+            5
+        } else {
+            1
+        };
+        if offset + len_bytes > size {
+            break Some(format!("entry at offset {} is truncated", offset));
+        }
+        let encoded = if is_32bit_str(b) {
+            5 + str_len_32bit(unsafe { lp.add(offset) }) as usize
+        } else {
+            get_encoded_size(unsafe { lp.add(offset) }) as usize
+        };
+        let backlen = backlen_size(encoded as u64) as usize;
+        let end = offset + encoded + backlen;
+        if end > size {
+            break Some(format!("entry at offset {} is truncated", offset));
+        }
+        let decoded = decode_backlen(unsafe { lp.add(end - 1) });
+        if decoded != encoded as u64 {
+            break Some(format!("backlen {} of the entry at offset {} doesn't match its size {}",
+                                decoded, offset, encoded));
+        }
+
+        entries.push(Entry {
+            offset,
+            encoding,
+            size: encoded,
+            backlen,
+            value: get(unsafe { lp.add(offset) }).to_owned_value(),
+        });
+        offset = end;
+    };
+
+    let error = error.or_else(|| {
+        let total = get_total_bytes(lp) as usize;
+        let count = get_num_elements(lp);
+        if total != size {
+            Some(format!("header says {} bytes but there are {}", total, size))
+        } else if count != HDR_NUMELE_UNKNOWN && count as usize != entries.len() {
+            Some(format!("header says {} elements but there are {}", count, entries.len()))
+        } else {
+            None
+        }
+    });
+    Inspection { entries, error }
+}
+
+fn header(bytes: &[u8]) -> Option<(u32, u16)> {
+    if bytes.len() < HDR {
+        return None;
+    }
+    let lp = bytes.as_ptr() as *mut u8;
+    Some((get_total_bytes(lp), get_num_elements(lp)))
+}
+
+fn dump(bytes: &[u8], inspection: &Inspection) {
+    if let Some((total, count)) = header(bytes) {
+        println!("total bytes:  {}", total);
+        if count == HDR_NUMELE_UNKNOWN {
+            println!("num elements: unknown");
+        } else {
+            println!("num elements: {}", count);
+        }
+    }
+    println!();
+    println!("{:>8}  {:<10} {:>6} {:>8}  value", "offset", "encoding", "size", "backlen");
+    for entry in &inspection.entries {
+        println!("{:>8}  {:<10} {:>6} {:>8}  {}",
+                 entry.offset, entry.encoding, entry.size, entry.backlen, display_value(&entry.value));
+    }
+    if let Some(ref error) = inspection.error {
+        println!();
+        println!("error: {}", error);
+    }
+}
+
+fn display_value(value: &OwnedValue) -> String {
+    match *value {
+        OwnedValue::Int(v) => v.to_string(),
+        OwnedValue::String(ref s) => match std::str::from_utf8(s) {
+            Ok(s) => format!("{:?}", s),
+            Err(_) => {
+                let escaped: Vec<u8> = s.iter().flat_map(|&b| std::ascii::escape_default(b)).collect();
+                format!("\"{}\"", String::from_utf8_lossy(&escaped))
+            }
+        },
+    }
+}
+
+fn print_stats(stats: &Stats) {
+    let ints = Encoding::ALL.iter()
+        .filter(|e| !e.is_string())
+        .map(|&e| stats.encoding(e).entries)
+        .sum::<usize>();
+
+    println!("total bytes:  {}", stats.total_bytes);
+    println!("entries:      {} ({} integers, {} strings)", stats.entries, ints, stats.entries - ints);
+    println!("header:       {} bytes, EOF included", stats.header_bytes);
+    println!("backlen:      {} bytes", stats.backlen_bytes);
+    println!("average size: {:.1} bytes per entry", stats.average_entry_size());
+    if stats.int_strings > 0 {
+        println!("int strings:  {} ({} bytes saved integer encoded)", stats.int_strings, stats.int_string_savings);
+    }
+    println!();
+    println!("{:<10} {:>8} {:>8}", "encoding", "entries", "bytes");
+    for &encoding in Encoding::ALL.iter() {
+        let e = stats.encoding(encoding);
+        if e.entries > 0 {
+            println!("{:<10} {:>8} {:>8}", encoding.name(), e.entries, e.bytes);
+        }
+    }
+}
+
+/// Values as a JSON array. Strings that aren't UTF-8 are converted lossily.
+fn to_json(entries: &[Entry]) -> String {
+    let mut json = String::from("[");
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        match entry.value {
+            OwnedValue::Int(v) => json.push_str(&v.to_string()),
+            OwnedValue::String(ref s) => {
+                json.push('"');
+                for c in String::from_utf8_lossy(s).chars() {
+                    match c {
+                        '"' => json.push_str("\\\""),
+                        '\\' => json.push_str("\\\\"),
+                        '\n' => json.push_str("\\n"),
+                        '\r' => json.push_str("\\r"),
+                        '\t' => json.push_str("\\t"),
+                        c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => json.push(c),
+                    }
+                }
+                json.push('"');
+            }
+        }
+    }
+    json.push(']');
+    json
+}
+
+/// Append 'values' to a new listpack and return its bytes.
+fn build(values: &[OwnedValue]) -> Result<Vec<u8>, String> {
+    let mut lp = Listpack::new();
+    for value in values {
+        if !lp.append(value.as_value()) {
+            return Err("the listpack can't grow that large".to_string());
+        }
+    }
+    Ok(lp.as_bytes().to_vec())
+}
+
+/// Parse the values to build a listpack of, as JSON when 'json' says so or
+/// when the input starts with '['.
+fn parse_values(input: &[u8], json: Option<bool>) -> Result<Vec<OwnedValue>, String> {
+    let json = json.unwrap_or_else(|| input.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'['));
+    if json {
+        JsonParser { input, pos: 0 }.values()
+    } else {
+        parse_lines(input)
+    }
+}
+
+fn parse_lines(input: &[u8]) -> Result<Vec<OwnedValue>, String> {
+    let mut values = Vec::new();
+    for (i, line) in input.split(|&b| b == b'\n').enumerate() {
+        let line = if line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            line
+        };
+        if line.iter().all(|b| b.is_ascii_whitespace()) || line.starts_with(b"#") {
+            continue;
+        }
+        let value = if line.starts_with(b"int:") {
+            std::str::from_utf8(&line[4..])
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .map(OwnedValue::Int)
+        } else if line.starts_with(b"str:") {
+            Some(OwnedValue::String(line[4..].to_vec()))
+        } else if line.starts_with(b"hex:") {
+            parse_hex(&line[4..]).ok().map(OwnedValue::String)
+        } else {
+            return Err(format!("line {}: expected an int:, str: or hex: prefix", i + 1));
+        };
+        match value {
+            Some(value) => values.push(value),
+            None => return Err(format!("line {}: invalid {}", i + 1, String::from_utf8_lossy(&line[..3]))),
+        }
+    }
+    Ok(values)
+}
+
+/// Parser of a JSON array of integers and strings.
+struct JsonParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn values(&mut self) -> Result<Vec<OwnedValue>, String> {
+        let mut values = Vec::new();
+        self.expect(b'[')?;
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+        } else {
+            loop {
+                values.push(self.value()?);
+                match self.next() {
+                    Some(b',') => continue,
+                    Some(b']') => break,
+                    _ => return Err(self.error("expected ',' or ']'")),
+                }
+            }
+        }
+        if self.peek().is_some() {
+            return Err(self.error("trailing characters after the array"));
+        }
+        Ok(values)
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("JSON at offset {}: {}", self.pos, message)
+    }
+
+    /// Next byte that isn't whitespace, without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        while self.pos < self.input.len() && self.input[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        self.input.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek();
+        self.pos += 1;
+        b
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), String> {
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", expected as char)))
+        }
+    }
+
+    fn value(&mut self) -> Result<OwnedValue, String> {
+        match self.peek() {
+            Some(b'"') => self.string().map(OwnedValue::String),
+            Some(b'-') | Some(b'0'..=b'9') => self.int().map(OwnedValue::Int),
+            _ => Err(self.error("expected an integer or a string")),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, String> {
+        let start = self.pos;
+        if self.input[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        while self.pos < self.input.len() && self.input[self.pos].is_ascii_digit() {
+            self.pos += 1;
+        }
+        if let Some(&b) = self.input.get(self.pos) {
+            if b == b'.' || b == b'e' || b == b'E' {
+                return Err(self.error("only integers are supported"));
+            }
+        }
+        std::str::from_utf8(&self.input[start..self.pos])
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| format!("JSON at offset {}: invalid 64 bit integer", start))
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        self.pos += 1;
+        let mut s = Vec::new();
+        loop {
+            let b = match self.input.get(self.pos) {
+                Some(&b) => b,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match b {
+                b'"' => return Ok(s),
+                b'\\' => {
+                    let escaped = self.input.get(self.pos).cloned();
+                    self.pos += 1;
+                    match escaped {
+                        Some(b'"') => s.push(b'"'),
+                        Some(b'\\') => s.push(b'\\'),
+                        Some(b'/') => s.push(b'/'),
+                        Some(b'b') => s.push(8),
+                        Some(b'f') => s.push(12),
+                        Some(b'n') => s.push(b'\n'),
+                        Some(b'r') => s.push(b'\r'),
+                        Some(b't') => s.push(b'\t'),
+                        Some(b'u') => {
+                            let c = self.unicode_escape()?;
+                            let mut buf = [0u8; 4];
+                            s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                b => s.push(b),
+            }
+        }
+    }
+
+    /// Decode the code point of a "\\u" escape, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            // A high surrogate must be followed by an escaped low one.
+            if !self.input[self.pos..].starts_with(b"\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else if (0xDC00..0xE000).contains(&high) {
+            return Err(self.error("unpaired surrogate"));
+        } else {
+            high
+        };
+        std::char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.input.get(self.pos..self.pos + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use listpack::Listpack;
+
+    fn sample() -> Vec<u8> {
+        let mut lp = Listpack::new();
+        lp.append(7);
+        lp.append("hello");
+        lp.append(-1000);
+        lp.append("a \"quoted\"\nline");
+        lp.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn inspect_entries() {
+        let bytes = sample();
+        let inspection = inspect(&bytes);
+        assert!(inspection.error.is_none());
+        let encodings: Vec<&str> = inspection.entries.iter().map(|e| e.encoding).collect();
+        assert_eq!(encodings, vec!["7bit uint", "6bit str", "13bit int", "6bit str"]);
+        assert_eq!(inspection.entries[1].offset, 6 + 1 + 1);
+        assert_eq!(inspection.entries[1].size, 6);
+        assert_eq!(to_json(&inspection.entries), r#"[7,"hello",-1000,"a \"quoted\"\nline"]"#);
+    }
+
+    #[test]
+    fn inspect_corrupt() {
+        let mut bytes = sample();
+        // Break the backlen of the second entry.
+        bytes[6 + 2 + 6] = 0x7F;
+        let inspection = inspect(&bytes);
+        assert_eq!(inspection.entries.len(), 1);
+        assert_eq!(inspection.error.unwrap(),
+                   "backlen 127 of the entry at offset 8 doesn't match its size 6");
+
+        let mut bytes = sample();
+        bytes.pop();
+        assert!(inspect(&bytes).error.is_some());
+        assert!(inspect(&bytes[..3]).error.is_some());
+    }
+
+    #[test]
+    fn build_values() {
+        let json = br#" [7, "hello", -1000, "a \u0022quoted\"\nline"] "#;
+        assert_eq!(build(&parse_values(json, None).unwrap()).unwrap(), sample());
+
+        let lines = b"# fixture\nint:7\r\nstr:hello\n\nint:-1000\nhex:612022717522\n";
+        let bytes = build(&parse_values(lines, None).unwrap()).unwrap();
+        assert_eq!(to_json(&inspect(&bytes).entries), r#"[7,"hello",-1000,"a \"qu\""]"#);
+        assert_eq!(parse_values(b"int:1\nhex:abc\n", None).unwrap_err(), "line 2: invalid hex");
+        assert_eq!(parse_values(b"int:1\n2\n", None).unwrap_err(),
+                   "line 2: expected an int:, str: or hex: prefix");
+
+        // Strings holding integers stay strings.
+        let bytes = build(&parse_values(b"str:12\nhex:3132\n", None).unwrap()).unwrap();
+        assert!(inspect(&bytes).entries.iter().all(|e| e.encoding == "6bit str"));
+
+        let emoji = parse_values(br#"["\ud83d\ude00"]"#, Some(true)).unwrap();
+        assert_eq!(emoji, vec![OwnedValue::String("\u{1F600}".as_bytes().to_vec())]);
+        assert_eq!(parse_values(br#"["\ud83d\u0041"]"#, None).unwrap_err(),
+                   "JSON at offset 14: unpaired surrogate");
+        assert_eq!(parse_values(br#"["\ude00"]"#, None).unwrap_err(),
+                   "JSON at offset 8: unpaired surrogate");
+        assert!(parse_values(br#"["\ud83d"]"#, None).is_err());
+        assert_eq!(parse_values(b"[1, []]", None).unwrap_err(),
+                   "JSON at offset 4: expected an integer or a string");
+        assert!(parse_values(b"[1.5]", None).is_err());
+        assert!(parse_values(b"[99999999999999999999]", None).is_err());
+        assert!(parse_values(b"[1] 2", None).is_err());
+        assert_eq!(parse_values(b"[ ]", None).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn decode_formats() {
+        let bytes = sample();
+        let hex: String = bytes.iter().map(|b| format!("{:02x} ", b)).collect();
+        assert_eq!(decode(hex.as_bytes(), Format::Auto).unwrap(), vec![bytes.clone()]);
+        assert_eq!(decode(format!("0x{}\n", hex.replace(" ", "")).as_bytes(), Format::Hex).unwrap(),
+                   vec![bytes.clone()]);
+        assert_eq!(decode(&bytes, Format::Auto).unwrap(), vec![bytes.clone()]);
+        assert!(parse_hex(b"abc").is_err());
+
+        // DUMP of a set: type, string, RDB version and checksum.
+        let mut dump = vec![RDB_TYPE_SET_LISTPACK, bytes.len() as u8];
+        dump.extend_from_slice(&bytes);
+        dump.extend_from_slice(&[11, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(decode(&dump, Format::Auto).unwrap(), vec![bytes.clone()]);
+        assert!(decode(&dump[..10], Format::Rdb).is_err());
+
+        // A quicklist of an LZF compressed node and a plain one.
+        let mut large = Listpack::new();
+        large.extend((0..100).map(|_| "repeated"));
+        let large = large.as_bytes().to_vec();
+        let compressed = lzf::compress(&large, large.len()).unwrap();
+        let mut dump = vec![RDB_TYPE_LIST_QUICKLIST_2, 2, 2, 0xC3];
+        dump.extend_from_slice(&[0x40 | (compressed.len() >> 8) as u8, compressed.len() as u8]);
+        dump.extend_from_slice(&[0x40 | (large.len() >> 8) as u8, large.len() as u8]);
+        dump.extend_from_slice(&compressed);
+        dump.extend_from_slice(&[1, 3, b'x', b'y', b'z']);
+        assert_eq!(decode(&dump, Format::Rdb).unwrap(), vec![large]);
+
+        // An LZF string claiming to expand to 2^64 - 16 bytes is left to be
+        // reported as a corrupt listpack.
+        let huge = [0x10, 0xC3, 0x01, 0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xF0, 0x00];
+        assert_eq!(decode(&huge, Format::Auto).unwrap(), vec![huge.to_vec()]);
+        assert!(decode(&huge, Format::Rdb).unwrap_err().contains("invalid LZF string length"));
+        assert!(lzf::decompress(&[0], usize::MAX).is_none());
+    }
+}
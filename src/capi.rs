@@ -0,0 +1,269 @@
+//! C ABI of the Rust implementation, compiled in with the `capi` feature.
+//!
+//! The functions mirror `c/listpack.h`, so C code written against the C
+//! library links against this crate instead, e.g. built with
+//! `cargo rustc --release --features capi --crate-type staticlib`. The
+//! matching header is generated by the build script from its table of these
+//! functions, available as `HEADER` and checked in as `include/listpack.h`.
+//! With the `c` feature as well, the C library is still available through
+//! `ffi` under prefixed symbols.
+//!
+//! Listpacks are allocated with the Rust global allocator and must be freed
+//! with `lpFree()`. Like in C, strings that are the canonical representation
+//! of an integer are stored integer encoded, and functions return NULL when
+//! the listpack can't grow.
+//!
+//! # Safety
+//!
+//! As in C, listpack and element pointers must be valid, with elements
+//! belonging to the listpack passed along, and a listpack must not be used
+//! after a call returned a new pointer for it.
+#![allow(clippy::missing_safety_doc)]
+
+use std;
+use std::io::Write;
+use std::os::raw::{c_int, c_long};
+
+use raw::*;
+
+/// The generated `listpack.h`.
+pub const HEADER: &str = include_str!(concat!(env!("OUT_DIR"), "/listpack.h"));
+
+/// Size of the buffer `lpGet()` may write an integer to: 20 digits of
+/// -2^63 + 1 null term.
+pub const LP_INTBUF_SIZE: usize = 21;
+
+pub const LP_BEFORE: c_int = 0;
+pub const LP_AFTER: c_int = 1;
+pub const LP_REPLACE: c_int = 2;
+
+/// Insert 'v' before or after 'p', or replace 'p' with it.
+unsafe fn insert_value(lp: listpack, v: Value, p: element, wh: c_int) -> Option<(listpack, element)> {
+    match wh {
+        LP_BEFORE => insert(ALLOCATOR, lp, v, Placement::Before, p),
+        LP_AFTER => insert(ALLOCATOR, lp, v, Placement::After, p),
+        _ => replace(ALLOCATOR, lp, p, v)
+    }
+}
+
+/// Delete 'p', returning the element that was on its right or NULL if it
+/// was the last one.
+unsafe fn delete_at(lp: listpack, p: element) -> Option<(listpack, element)> {
+    let (lp, p) = delete_range_with_entry(ALLOCATOR, lp, p, 1)?;
+    if *p == EOF {
+        Some((lp, std::ptr::null_mut()))
+    } else {
+        Some((lp, p))
+    }
+}
+
+/// Store the element in 'newp', if any, and return the listpack or NULL.
+unsafe fn finish(result: Option<(listpack, element)>, newp: *mut element) -> listpack {
+    match result {
+        Some((lp, p)) => {
+            if !newp.is_null() {
+                *newp = p;
+            }
+            lp
+        }
+        None => std::ptr::null_mut()
+    }
+}
+
+#[inline]
+unsafe fn ele_value(ele: *mut u8, size: u32) -> Value {
+    Value::compact(std::slice::from_raw_parts(ele, size as usize))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpNew() -> listpack {
+    new(ALLOCATOR)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpFree(lp: listpack) {
+    if !lp.is_null() {
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize)
+    }
+}
+
+/// Insert 'ele' of 'size' bytes before or after 'p', or replace 'p' with it,
+/// depending on 'where'. When 'ele' is NULL 'p' is deleted instead. 'newp'
+/// is set to the element added, or the one after the deleted one.
+#[no_mangle]
+pub unsafe extern "C" fn lpInsert(lp: listpack, ele: *mut u8, size: u32, p: element, wh: c_int, newp: *mut element) -> listpack {
+    if ele.is_null() {
+        finish(delete_at(lp, p), newp)
+    } else {
+        finish(insert_value(lp, ele_value(ele, size), p, wh), newp)
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpAppend(lp: listpack, ele: *mut u8, size: u32) -> listpack {
+    append(ALLOCATOR, lp, ele_value(ele, size)).unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpDelete(lp: listpack, p: element, newp: *mut element) -> listpack {
+    finish(delete_at(lp, p), newp)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpLength(lp: listpack) -> u32 {
+    length(lp)
+}
+
+/// Return the string of the element 'p' and set 'count' to its length. An
+/// integer is written to 'intbuf' as a null terminated string if given,
+/// otherwise 'count' is set to the integer and NULL is returned.
+#[no_mangle]
+pub unsafe extern "C" fn lpGet(p: element, count: *mut i64, intbuf: *mut u8) -> *mut u8 {
+    match get(p) {
+        Value::Int(v) if intbuf.is_null() => {
+            *count = v;
+            std::ptr::null_mut()
+        }
+        Value::Int(v) => {
+            let buf = std::slice::from_raw_parts_mut(intbuf, LP_INTBUF_SIZE);
+            let len = {
+                let mut w = &mut buf[..];
+                let _ = write!(w, "{}", v);
+                LP_INTBUF_SIZE - w.len()
+            };
+            buf[len] = 0;
+            *count = len as i64;
+            intbuf
+        }
+        Value::String(s, len) => {
+            *count = len as i64;
+            s as *mut u8
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpFirst(lp: listpack) -> element {
+    first(lp).unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpLast(lp: listpack) -> element {
+    last(lp).unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpNext(lp: listpack, p: element) -> element {
+    next(lp, p).unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpPrev(lp: listpack, p: element) -> element {
+    prev(lp, p).unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpBytes(lp: listpack) -> u32 {
+    get_total_bytes(lp)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpSeek(lp: listpack, index: c_long) -> element {
+    seek(lp, index as isize).unwrap_or(std::ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpInsertInt64(lp: listpack, value: i64, p: element, wh: c_int, newp: *mut element) -> listpack {
+    finish(insert_value(lp, Value::Int(value), p, wh), newp)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lpAppendInt64(lp: listpack, value: i64) -> listpack {
+    append(ALLOCATOR, lp, Value::Int(value)).unwrap_or(std::ptr::null_mut())
+}
+
+/// Replace the element at '*pos' with 'value', updating '*pos'.
+#[no_mangle]
+pub unsafe extern "C" fn lpReplaceInt64(lp: listpack, pos: *mut element, value: i64) -> listpack {
+    finish(replace(ALLOCATOR, lp, *pos, Value::Int(value)), pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn get_string(p: element) -> String {
+        let mut count = 0i64;
+        let mut buf = [0u8; LP_INTBUF_SIZE];
+        let s = lpGet(p, &mut count, buf.as_mut_ptr());
+        String::from_utf8(std::slice::from_raw_parts(s, count as usize).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn like_c() {
+        unsafe {
+            let mut lp = lpNew();
+            lp = lpAppend(lp, b"hello".as_ptr() as *mut u8, 5);
+            lp = lpAppend(lp, b"1024".as_ptr() as *mut u8, 4);
+            lp = lpAppendInt64(lp, -7);
+            assert_eq!(lpLength(lp), 3);
+
+            // Integer strings are integer encoded.
+            let mut count = 0i64;
+            let p = lpSeek(lp, 1);
+            assert!(lpGet(p, &mut count, std::ptr::null_mut()).is_null());
+            assert_eq!(count, 1024);
+            assert_eq!(get_string(p), "1024");
+            assert_eq!(get_string(lpLast(lp)), "-7");
+
+            let mut newp = std::ptr::null_mut();
+            lp = lpInsert(lp, b"first".as_ptr() as *mut u8, 5, lpFirst(lp), LP_BEFORE, &mut newp);
+            assert_eq!(get_string(newp), "first");
+            lp = lpInsertInt64(lp, 5, lpFirst(lp), LP_AFTER, &mut newp);
+            assert_eq!(get_string(lpPrev(lp, newp)), "first");
+            lp = lpReplaceInt64(lp, &mut newp, 6);
+            assert_eq!(get_string(newp), "6");
+            lp = lpInsert(lp, b"x".as_ptr() as *mut u8, 1, newp, LP_REPLACE, &mut newp);
+            assert_eq!(get_string(lpNext(lp, newp)), "hello");
+
+            let mut p = lpFirst(lp);
+            let mut all = Vec::new();
+            while !p.is_null() {
+                all.push(get_string(p));
+                p = lpNext(lp, p);
+            }
+            assert_eq!(all, vec!["first", "x", "hello", "1024", "-7"]);
+
+            // Deleting the last element sets 'newp' to NULL.
+            lp = lpDelete(lp, lpLast(lp), &mut newp);
+            assert!(newp.is_null());
+            lp = lpInsert(lp, std::ptr::null_mut(), 0, lpFirst(lp), LP_REPLACE, &mut newp);
+            assert_eq!(get_string(newp), "x");
+            assert_eq!(lpLength(lp), 3);
+            assert_eq!(lpBytes(lp) as usize, get_total_bytes(lp) as usize);
+            assert!(lpSeek(lp, 3).is_null());
+            lpFree(lp);
+        }
+    }
+
+    #[test]
+    fn header() {
+        assert!(HEADER.contains("#define LP_BEFORE 0\n"));
+        assert!(HEADER.contains("#define LP_INTBUF_SIZE 21\n"));
+        assert!(HEADER.contains("unsigned char *lpNew(void);\n"));
+        assert!(HEADER.contains(
+            "unsigned char *lpInsert(unsigned char *lp, unsigned char *ele, uint32_t size, \
+             unsigned char *p, int where, unsigned char **newp);\n"));
+        assert!(HEADER.contains("unsigned char *lpGet(unsigned char *p, int64_t *count, unsigned char *intbuf);\n"));
+        assert!(HEADER.contains("unsigned char *lpSeek(unsigned char *lp, long index);\n"));
+        assert!(HEADER.contains("void lpFree(unsigned char *lp);\n"));
+        assert_eq!(HEADER.matches(");\n").count(), 16);
+    }
+
+    #[test]
+    fn checked_in_header() {
+        // After changing the functions of build.rs, copy the generated
+        // header over include/listpack.h.
+        assert_eq!(HEADER, include_str!("../include/listpack.h"));
+    }
+}
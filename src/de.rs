@@ -0,0 +1,678 @@
+//! Deserialize Rust values from a listpack written by the `ser` module.
+//!
+//! Strings are borrowed from the listpack when possible. Integer entries are
+//! also accepted where strings are expected, and decimal strings where
+//! integers or floats are expected, so listpacks written by Redis, which
+//! integer encodes numeric strings, can be read too.
+//!
+//! It also implements `Deserialize` for `Listpack` and `OwnedValue`.
+
+use std;
+use std::convert::TryFrom;
+use std::fmt;
+
+use serde::de::{self, Deserialize, DeserializeSeed, IntoDeserializer, SeqAccess, Visitor};
+
+use ::error::Error;
+use ::raw::*;
+use ::Listpack;
+
+/// Deserialize a 'T' from all the entries of 'lp'.
+pub fn from_listpack<'de, T: Deserialize<'de>>(lp: &'de Listpack) -> Result<T, Error> {
+    let mut deserializer = Deserializer::new(lp);
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Deserializer walking the entries of a listpack.
+pub struct Deserializer<'de> {
+    lp: &'de Listpack,
+    ele: Option<element>,
+}
+
+impl<'de> Deserializer<'de> {
+    pub fn new(lp: &'de Listpack) -> Deserializer<'de> {
+        Deserializer {
+            lp,
+            ele: first(lp.0),
+        }
+    }
+
+    /// Check that all the entries were consumed.
+    pub fn end(&self) -> Result<(), Error> {
+        match self.ele {
+            Some(_) => Err(Error::TrailingEntries),
+            None => Ok(())
+        }
+    }
+
+    fn next_value(&mut self) -> Result<Value, Error> {
+        let p = self.ele.ok_or(Error::Eof)?;
+        self.ele = next(self.lp.0, p);
+        Ok(get(p))
+    }
+
+    fn next_int(&mut self) -> Result<i64, Error> {
+        self.next_value()?.as_int().ok_or(Error::ExpectedInt)
+    }
+
+    fn next_len(&mut self) -> Result<usize, Error> {
+        let len = self.next_int()?;
+        usize::try_from(len).map_err(|_| Error::IntOutOfRange)
+    }
+
+    /// The bytes of a string entry, borrowed from the listpack.
+    fn next_bytes(&mut self) -> Result<Result<&'de [u8], i64>, Error> {
+        match self.next_value()? {
+            Value::Int(v) => Ok(Err(v)),
+            Value::String(ptr, len) => Ok(Ok(unsafe {
+                std::slice::from_raw_parts(ptr, len as usize)
+            }))
+        }
+    }
+
+    fn next_i128(&mut self) -> Result<i128, Error> {
+        match self.next_bytes()? {
+            Err(v) => Ok(v as i128),
+            Ok(bytes) if bytes.len() == 16 => {
+                let mut buf = [0u8; 16];
+                buf.copy_from_slice(bytes);
+                Ok(i128::from_le_bytes(buf))
+            }
+            Ok(_) => Err(Error::ExpectedInt)
+        }
+    }
+
+    /// Floats are written by their bits. Decimal strings are accepted too.
+    fn next_float(&mut self, single: bool) -> Result<f64, Error> {
+        match self.next_bytes()? {
+            Err(bits) if single => Ok(f32::from_bits(bits as u32) as f64),
+            Err(bits) => Ok(f64::from_bits(bits as u64)),
+            Ok(bytes) => std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(Error::ExpectedInt)
+        }
+    }
+
+    fn seq<V: Visitor<'de>>(&mut self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let value = visitor.visit_seq(Access { de: self, remaining: len })?;
+        Ok(value)
+    }
+}
+
+macro_rules! deserialize_int {
+    ($method:ident, $visit:ident, $ty:ty) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            let v = self.next_int()?;
+            visitor.$visit(<$ty>::try_from(v).map_err(|_| Error::IntOutOfRange)?)
+        }
+    };
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    /// The format isn't self-describing, so only scalars are supported:
+    /// integers as `i64` and strings as `str`, or bytes if not UTF-8.
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.next_bytes()? {
+            Err(v) => visitor.visit_i64(v),
+            Ok(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(_) => visitor.visit_borrowed_bytes(bytes)
+            }
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_bool(self.next_int()? != 0)
+    }
+
+    deserialize_int!(deserialize_i8, visit_i8, i8);
+    deserialize_int!(deserialize_i16, visit_i16, i16);
+    deserialize_int!(deserialize_i32, visit_i32, i32);
+    deserialize_int!(deserialize_u8, visit_u8, u8);
+    deserialize_int!(deserialize_u16, visit_u16, u16);
+    deserialize_int!(deserialize_u32, visit_u32, u32);
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i64(self.next_int()?)
+    }
+
+    /// Written the way `Into<Value>` does, wrapping values above `i64::MAX`.
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u64(self.next_int()? as u64)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_i128(self.next_i128()?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_u128(self.next_i128()? as u128)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f32(self.next_float(true)? as f32)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_f64(self.next_float(false)?)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let v = u32::try_from(self.next_int()?).map_err(|_| Error::IntOutOfRange)?;
+        visitor.visit_char(std::char::from_u32(v).ok_or(Error::IntOutOfRange)?)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.next_bytes()? {
+            Err(v) => visitor.visit_string(v.to_string()),
+            Ok(bytes) => visitor.visit_borrowed_str(
+                std::str::from_utf8(bytes).map_err(|_| Error::InvalidUtf8)?
+            )
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.next_bytes()? {
+            Err(v) => visitor.visit_byte_buf(v.to_string().into_bytes()),
+            Ok(bytes) => visitor.visit_borrowed_bytes(bytes)
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.next_int()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            _ => Err(Error::IntOutOfRange)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.next_len()?;
+        self.seq(len, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.seq(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.seq(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.next_len()?;
+        visitor.visit_map(Access { de: self, remaining: len })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self)
+    }
+
+    /// Only variant indexes are stored.
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Access to the elements of a sequence or the pairs of a map.
+struct Access<'a, 'de: 'a> {
+    de: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Access<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Access<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let index = u32::try_from(self.next_int()?).map_err(|_| Error::IntOutOfRange)?;
+        let value = seed.deserialize(index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.seq(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+}
+
+/// Variant of a binary serialized value, by index or by name.
+enum Variant {
+    Int,
+    String,
+}
+
+impl<'de> Deserialize<'de> for Variant {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Variant, D::Error> {
+        struct VariantVisitor;
+
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = Variant;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("`Int` or `String`")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Variant, E> {
+                match v {
+                    0 => Ok(Variant::Int),
+                    1 => Ok(Variant::String),
+                    _ => Err(E::invalid_value(de::Unexpected::Unsigned(v), &self))
+                }
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Variant, E> {
+                match v {
+                    "Int" => Ok(Variant::Int),
+                    "String" => Ok(Variant::String),
+                    _ => Err(E::unknown_variant(v, VARIANTS))
+                }
+            }
+        }
+
+        deserializer.deserialize_identifier(VariantVisitor)
+    }
+}
+
+const VARIANTS: &[&str] = &["Int", "String"];
+
+/// Bytes deserialized with `deserialize_byte_buf()` rather than as a
+/// sequence.
+struct ByteBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteBuf {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<ByteBuf, D::Error> {
+        deserializer.deserialize_byte_buf(OwnedValueVisitor).and_then(|v| match v {
+            OwnedValue::String(bytes) => Ok(ByteBuf(bytes)),
+            OwnedValue::Int(v) => Err(de::Error::invalid_type(de::Unexpected::Signed(v), &"bytes"))
+        })
+    }
+}
+
+struct OwnedValueVisitor;
+
+impl<'de> Visitor<'de> for OwnedValueVisitor {
+    type Value = OwnedValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an integer, a string or bytes")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<OwnedValue, E> {
+        Ok(OwnedValue::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<OwnedValue, E> {
+        i64::try_from(v)
+            .map(OwnedValue::Int)
+            .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<OwnedValue, E> {
+        Ok(OwnedValue::String(v.as_bytes().to_vec()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<OwnedValue, E> {
+        Ok(OwnedValue::String(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<OwnedValue, E> {
+        Ok(OwnedValue::String(v))
+    }
+
+    /// Bytes in formats that write them as a sequence, like JSON.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OwnedValue, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element::<u8>()? {
+            bytes.push(b);
+        }
+        Ok(OwnedValue::String(bytes))
+    }
+
+    fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<OwnedValue, A::Error> {
+        use serde::de::VariantAccess;
+        match data.variant()? {
+            (Variant::Int, variant) => variant.newtype_variant().map(OwnedValue::Int),
+            (Variant::String, variant) => variant.newtype_variant::<ByteBuf>()
+                .map(|b| OwnedValue::String(b.0))
+        }
+    }
+}
+
+/// The counterpart of `Serialize for Value`.
+impl<'de> Deserialize<'de> for OwnedValue {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<OwnedValue, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(OwnedValueVisitor)
+        } else {
+            deserializer.deserialize_enum("Value", VARIANTS, OwnedValueVisitor)
+        }
+    }
+}
+
+struct ListpackVisitor;
+
+impl<'de> Visitor<'de> for ListpackVisitor {
+    type Value = Listpack;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of values or listpack bytes")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Listpack, A::Error> {
+        let mut lp = Listpack::new();
+        while let Some(v) = seq.next_element::<OwnedValue>()? {
+            if !lp.append_val(v.as_value()) {
+                return Err(de::Error::custom(Error::Alloc));
+            }
+        }
+        Ok(lp)
+    }
+
+    /// The bytes are validated before being copied.
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Listpack, E> {
+        Listpack::from_bytes(v)
+            .map_err(|_| E::invalid_value(de::Unexpected::Bytes(v), &"a valid listpack"))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Listpack, E> {
+        Listpack::from_vec(v)
+            .map_err(|_| E::invalid_value(de::Unexpected::Other("byte buffer"), &"a valid listpack"))
+    }
+}
+
+/// The counterpart of `Serialize for Listpack`.
+impl<'de> Deserialize<'de> for Listpack {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Listpack, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_seq(ListpackVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(ListpackVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate bincode;
+    extern crate serde_json;
+
+    use super::*;
+    use std::collections::BTreeMap;
+    use ::ser::to_listpack;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: u32, h: u32 },
+        Line(i8, i8),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record<'a> {
+        id: u64,
+        name: &'a str,
+        tags: Vec<String>,
+        props: BTreeMap<String, i64>,
+        shapes: Vec<Shape>,
+        parent: Option<Box<Record<'a>>>,
+        big: i128,
+        flag: bool,
+        initial: char,
+        ratio: f32,
+        unit: (),
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut props = BTreeMap::new();
+        props.insert("a".to_string(), -1);
+        props.insert("b".to_string(), i64::MAX);
+        let record = Record {
+            id: u64::MAX,
+            name: "listpack",
+            tags: vec!["x".to_string(), "12".to_string(), String::new()],
+            props,
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(0.5),
+                Shape::Rect { w: 3, h: 4 },
+                Shape::Line(-1, 1),
+            ],
+            parent: Some(Box::new(Record {
+                id: 0,
+                name: "",
+                tags: Vec::new(),
+                props: BTreeMap::new(),
+                shapes: Vec::new(),
+                parent: None,
+                big: 0,
+                flag: false,
+                initial: 'a',
+                ratio: 0.0,
+                unit: (),
+            })),
+            big: i128::MIN,
+            flag: true,
+            initial: 'é',
+            ratio: 1.25,
+            unit: (),
+        };
+
+        let lp = to_listpack(&record).unwrap();
+        let decoded: Record = from_listpack(&lp).unwrap();
+        assert_eq!(decoded, record);
+        // Strings are borrowed from the listpack.
+        assert!(lp.0 as usize <= decoded.name.as_ptr() as usize);
+    }
+
+    #[test]
+    fn errors() {
+        let lp = to_listpack(&(1u32, 2u32)).unwrap();
+        assert_eq!(from_listpack::<u32>(&lp), Err(Error::TrailingEntries));
+        assert_eq!(from_listpack::<(u32, u32, u32)>(&lp), Err(Error::Eof));
+
+        let lp = to_listpack(&300u32).unwrap();
+        assert_eq!(from_listpack::<u8>(&lp), Err(Error::IntOutOfRange));
+
+        let lp = to_listpack(&"abc").unwrap();
+        assert_eq!(from_listpack::<u32>(&lp), Err(Error::ExpectedInt));
+        let mut lp = Listpack::new();
+        lp.append_val((&b"\xff"[..]).into());
+        assert_eq!(from_listpack::<String>(&lp), Err(Error::InvalidUtf8));
+    }
+
+    fn sample() -> Listpack {
+        let mut lp = Listpack::new();
+        lp.append_val(Value::Int(-7));
+        lp.append_val("text".into());
+        lp.append_val((&b"\x00\xff"[..]).into());
+        lp.append_val(Value::Int(i64::MAX));
+        lp
+    }
+
+    fn owned(lp: &Listpack) -> Vec<OwnedValue> {
+        let mut values = Vec::new();
+        let mut ele = first(lp.0);
+        while let Some(p) = ele {
+            values.push(get(p).to_owned_value());
+            ele = next(lp.0, p);
+        }
+        values
+    }
+
+    #[test]
+    fn listpack_json() {
+        let lp = sample();
+        let json = serde_json::to_string(&lp).unwrap();
+        assert_eq!(json, r#"[-7,"text",[0,255],9223372036854775807]"#);
+
+        let decoded: Listpack = serde_json::from_str(&json).unwrap();
+        assert_eq!(owned(&decoded), owned(&lp));
+        assert_eq!(get_total_bytes(decoded.0), get_total_bytes(lp.0));
+
+        let v: OwnedValue = serde_json::from_str("42").unwrap();
+        assert_eq!(v, OwnedValue::Int(42));
+        assert!(serde_json::from_str::<OwnedValue>("1.5").is_err());
+    }
+
+    #[test]
+    fn listpack_binary() {
+        let lp = sample();
+        let bytes = bincode::serialize(&lp).unwrap();
+        let size = get_total_bytes(lp.0) as usize;
+        // A length prefix followed by the listpack itself.
+        assert_eq!(bytes.len(), 8 + size);
+        assert_eq!(&bytes[8..], unsafe { std::slice::from_raw_parts(lp.0, size) });
+
+        let decoded: Listpack = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(owned(&decoded), owned(&lp));
+
+        let mut corrupt = bytes.clone();
+        corrupt[8 + 6] = 0xF5;
+        assert!(bincode::deserialize::<Listpack>(&corrupt).is_err());
+
+        let values = owned(&lp);
+        let bytes = bincode::serialize(&values).unwrap();
+        assert_eq!(bincode::deserialize::<Vec<OwnedValue>>(&bytes).unwrap(), values);
+
+        // The listpack format is binary too.
+        let nested = to_listpack(&lp).unwrap();
+        assert_eq!(length(nested.0), 1);
+        let decoded: Listpack = from_listpack(&nested).unwrap();
+        assert_eq!(owned(&decoded), owned(&lp));
+    }
+
+    #[test]
+    fn redis_encodings() {
+        // Numeric strings are integer encoded by Redis.
+        let mut lp = Listpack::new();
+        lp.append_val(Value::compact(b"42"));
+        lp.append_val(Value::compact(b"2.5"));
+        let (s, f): (String, f64) = from_listpack(&lp).unwrap();
+        assert_eq!(s, "42");
+        assert_eq!(f, 2.5);
+    }
+}
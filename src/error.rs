@@ -0,0 +1,57 @@
+use std;
+use std::fmt;
+
+use serde::{de, ser};
+
+/// Error raised while serializing into or deserializing from a listpack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Custom error reported by a `Serialize` or `Deserialize` impl.
+    Message(String),
+    /// The listpack could not grow.
+    Alloc,
+    /// The entries ran out before the value was complete.
+    Eof,
+    /// Entries were left after the value was complete.
+    TrailingEntries,
+    /// An integer entry was expected.
+    ExpectedInt,
+    /// A string entry was expected.
+    ExpectedString,
+    /// A string entry isn't valid UTF-8.
+    InvalidUtf8,
+    /// An integer doesn't fit the requested type.
+    IntOutOfRange,
+    /// The type can't be represented without a schema.
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Message(ref msg) => f.write_str(msg),
+            Error::Alloc => f.write_str("listpack allocation failed"),
+            Error::Eof => f.write_str("unexpected end of listpack"),
+            Error::TrailingEntries => f.write_str("trailing listpack entries"),
+            Error::ExpectedInt => f.write_str("expected an integer entry"),
+            Error::ExpectedString => f.write_str("expected a string entry"),
+            Error::InvalidUtf8 => f.write_str("string entry is not valid UTF-8"),
+            Error::IntOutOfRange => f.write_str("integer out of range"),
+            Error::Unsupported(what) => write!(f, "{} is not supported", what),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
@@ -0,0 +1,214 @@
+//! Bindings to the C listpack library, compiled in with the `c` feature.
+//!
+//! The C library allocates through the `lp_malloc`, `lp_realloc` and
+//! `lp_free` hooks, which default to libc. `install()` routes them through a
+//! `raw::Allocator`, after which listpacks made by C and by Rust with that
+//! allocator are the same kind of allocation: `from_c()` and `into_c()` hand
+//! them over from one side to the other.
+
+use libc;
+use std;
+use std::any::TypeId;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+use raw::*;
+use ::Listpack;
+
+/// `lpInsert()` placements.
+pub const LP_BEFORE: libc::c_int = 0;
+pub const LP_AFTER: libc::c_int = 1;
+pub const LP_REPLACE: libc::c_int = 2;
+
+extern "C" {
+    pub static mut lp_malloc: extern "C" fn(size: libc::size_t) -> *mut libc::c_void;
+    pub static mut lp_realloc: extern "C" fn(ptr: *mut libc::c_void, size: libc::size_t) -> *mut libc::c_void;
+    pub static mut lp_free: extern "C" fn(ptr: *mut libc::c_void);
+
+    // With the `capi` feature these names are taken by the Rust
+    // implementation, and build.rs compiles the C ones with a "c_" prefix.
+    #[cfg_attr(feature = "capi", link_name = "c_lpNew")]
+    pub fn lpNew() -> listpack;
+    #[cfg_attr(feature = "capi", link_name = "c_lpFree")]
+    pub fn lpFree(lp: listpack);
+    #[cfg_attr(feature = "capi", link_name = "c_lpInsert")]
+    pub fn lpInsert(lp: listpack, ele: *const u8, size: u32, p: element, wh: libc::c_int, newp: *mut element) -> listpack;
+    #[cfg_attr(feature = "capi", link_name = "c_lpAppend")]
+    pub fn lpAppend(lp: listpack, ele: *const u8, size: u32) -> listpack;
+    #[cfg_attr(feature = "capi", link_name = "c_lpDelete")]
+    pub fn lpDelete(lp: listpack, p: element, newp: *mut element) -> listpack;
+    #[cfg_attr(feature = "capi", link_name = "c_lpLength")]
+    pub fn lpLength(lp: listpack) -> u32;
+    #[cfg_attr(feature = "capi", link_name = "c_lpGet")]
+    pub fn lpGet(p: element, count: *mut i64, intbuf: *mut u8) -> *mut u8;
+    #[cfg_attr(feature = "capi", link_name = "c_lpFirst")]
+    pub fn lpFirst(lp: listpack) -> element;
+    #[cfg_attr(feature = "capi", link_name = "c_lpLast")]
+    pub fn lpLast(lp: listpack) -> element;
+    #[cfg_attr(feature = "capi", link_name = "c_lpNext")]
+    pub fn lpNext(lp: listpack, p: element) -> element;
+    #[cfg_attr(feature = "capi", link_name = "c_lpPrev")]
+    pub fn lpPrev(lp: listpack, p: element) -> element;
+    #[cfg_attr(feature = "capi", link_name = "c_lpBytes")]
+    pub fn lpBytes(lp: listpack) -> u32;
+    #[cfg_attr(feature = "capi", link_name = "c_lpSeek")]
+    pub fn lpSeek(lp: listpack, index: libc::c_long) -> element;
+    #[cfg_attr(feature = "capi", link_name = "c_lpAppendInt64")]
+    pub fn lpAppendInt64(lp: listpack, value: i64) -> listpack;
+    #[cfg_attr(feature = "capi", link_name = "c_lpInsertInt64")]
+    pub fn lpInsertInt64(lp: listpack, value: i64, p: element, wh: libc::c_int, newp: *mut element) -> listpack;
+    #[cfg_attr(feature = "capi", link_name = "c_lpReplaceInt64")]
+    pub fn lpReplaceInt64(lp: listpack, pos: *mut element, value: i64) -> listpack;
+}
+
+/// Allocator the hooks forward to.
+static HOOKED: AtomicPtr<()> = AtomicPtr::new(std::ptr::null_mut());
+/// Type and address of the installed allocator.
+static INSTALLED: Mutex<Option<(TypeId, usize)>> = Mutex::new(None);
+
+/// Replace the C allocation hooks.
+///
+/// # Safety
+///
+/// No listpack allocated by C may be alive, and no C listpack function may
+/// run concurrently. The hooks conflict with `install()`.
+pub unsafe fn set_allocator(
+    malloc: extern "C" fn(size: libc::size_t) -> *mut libc::c_void,
+    realloc: extern "C" fn(ptr: *mut libc::c_void, size: libc::size_t) -> *mut libc::c_void,
+    free: extern "C" fn(ptr: *mut libc::c_void)) {
+    lp_malloc = malloc;
+    lp_realloc = realloc;
+    lp_free = free;
+}
+
+/// Route the C allocation hooks through 'allocator' for the rest of the
+/// process. Only the first call installs anything, later ones return false.
+///
+/// Install before the C library is used: a listpack it allocated with libc
+/// must not be grown or freed afterwards, which the unsafe C functions
+/// leave up to their callers.
+pub fn install<A: Allocator + Sync + 'static>(allocator: &'static A) -> bool {
+    let mut installed = INSTALLED.lock().unwrap();
+    if installed.is_some() {
+        return false;
+    }
+    *installed = Some(identify(allocator));
+    HOOKED.store(allocator as *const A as *mut (), Ordering::Release);
+    unsafe {
+        set_allocator(malloc_hook::<A>, realloc_hook::<A>, free_hook::<A>);
+    }
+    true
+}
+
+/// Route the C allocation hooks through the Rust global allocator. See
+/// `install()`.
+pub fn install_global() -> bool {
+    install(ALLOCATOR)
+}
+
+/// Return true if 'allocator' is the one the C hooks forward to.
+pub fn is_installed<A: Allocator + 'static>(allocator: &A) -> bool {
+    *INSTALLED.lock().unwrap() == Some(identify(allocator))
+}
+
+/// Hand a listpack over to C, which may grow and free it from now on.
+/// Fails if its allocator isn't the installed one.
+pub fn into_c<A: Allocator + Sync + 'static>(lp: Listpack<&'static A>) -> Result<listpack, Listpack<&'static A>> {
+    if !is_installed(lp.1) {
+        return Err(lp);
+    }
+    let lp = std::mem::ManuallyDrop::new(lp);
+    Ok(lp.0)
+}
+
+/// Take ownership of a listpack made by C. Returns `None` if 'allocator'
+/// isn't the installed one.
+///
+/// # Safety
+///
+/// 'lp' must be a valid listpack allocated by C after 'allocator' was
+/// installed, and C must not use it anymore.
+pub unsafe fn from_c<A: Allocator + Sync + 'static>(lp: listpack, allocator: &'static A) -> Option<Listpack<&'static A>> {
+    if is_installed(allocator) {
+        Some(Listpack(lp, allocator))
+    } else {
+        None
+    }
+}
+
+/// Instances of a zero sized allocator are interchangeable, but their
+/// addresses needn't be equal.
+fn identify<A: Allocator + 'static>(allocator: &A) -> (TypeId, usize) {
+    let address = if std::mem::size_of::<A>() == 0 {
+        0
+    } else {
+        allocator as *const A as usize
+    };
+    (TypeId::of::<A>(), address)
+}
+
+#[inline]
+fn hooked<A: Allocator + 'static>() -> &'static A {
+    unsafe { &*(HOOKED.load(Ordering::Acquire) as *const A) }
+}
+
+extern "C" fn malloc_hook<A: Allocator + 'static>(size: libc::size_t) -> *mut libc::c_void {
+    hooked::<A>().alloc(size) as *mut libc::c_void
+}
+
+// The C library only allocates listpacks and updates the header after a
+// reallocation, so the header holds the size of the allocation.
+extern "C" fn realloc_hook<A: Allocator + 'static>(ptr: *mut libc::c_void, size: libc::size_t) -> *mut libc::c_void {
+    if ptr.is_null() {
+        return malloc_hook::<A>(size);
+    }
+    let lp = ptr as listpack;
+    hooked::<A>().realloc(lp, get_total_bytes(lp) as usize, size) as *mut libc::c_void
+}
+
+extern "C" fn free_hook<A: Allocator + 'static>(ptr: *mut libc::c_void) {
+    if !ptr.is_null() {
+        let lp = ptr as listpack;
+        hooked::<A>().dealloc(lp, get_total_bytes(lp) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_ownership() {
+        assert!(!is_installed(ALLOCATOR));
+        let rust = Listpack::new_in(ALLOCATOR);
+        let rust = into_c(rust).err().unwrap();
+
+        assert!(install_global());
+        assert!(!install_global());
+        assert!(is_installed(ALLOCATOR));
+
+        unsafe {
+            // Made by C, grown by Rust.
+            let mut lp = lpNew();
+            lp = lpAppend(lp, b"hello".as_ptr(), 5);
+            let mut from = from_c(lp, ALLOCATOR).unwrap();
+            assert!(from.append(42));
+            assert_eq!(from.get_str(first(from.0).unwrap()), "hello");
+            assert_eq!(from.get_int(last(from.0).unwrap()), 42);
+
+            // Made by Rust, grown and freed by C.
+            let mut lp = into_c(rust).ok().unwrap();
+            lp = lpAppendInt64(lp, 7);
+            lp = lpAppend(lp, b"world".as_ptr(), 5);
+            assert_eq!(lpLength(lp), 2);
+            assert_eq!(get_int(first(lp).unwrap()), 7);
+            lpFree(lp);
+
+            // And back to C.
+            let lp = into_c(from).ok().unwrap();
+            let lp = lpDelete(lp, lpFirst(lp), std::ptr::null_mut());
+            assert_eq!(lpLength(lp), 1);
+            lpFree(lp);
+        }
+    }
+}
@@ -1,25 +1,103 @@
+#[cfg(feature = "c")]
+extern crate libc;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+
 use raw::*;
 
+pub mod arena;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "serde")]
+pub mod error;
+#[cfg(feature = "c")]
+pub mod ffi;
+pub mod lzf;
+pub mod map;
+pub mod quicklist;
 pub mod raw;
 pub mod segment;
+#[cfg(feature = "serde")]
+pub mod ser;
+pub mod set;
+pub mod stream;
+pub mod tracking;
+pub mod zset;
+
+#[cfg(feature = "serde")]
+pub use de::{from_listpack, Deserializer};
+#[cfg(feature = "serde")]
+pub use error::Error;
+#[cfg(feature = "serde")]
+pub use ser::{to_listpack, Serializer};
 
 pub trait ListpackLike {}
 
-pub struct Listpack(listpack);
+/// A listpack owning its allocation. Allocations go through 'A', which by
+/// default is a `CapacityAllocator` on top of the system allocator.
+pub struct Listpack<A: Allocator = CapacityAllocator>(listpack, A);
 
 impl Listpack {
     pub fn new() -> Listpack {
-        Listpack(new(ALLOCATOR))
+        let allocator = CapacityAllocator::new();
+        Listpack(new(&allocator), allocator)
+    }
+
+    /// Create an empty listpack with room for at least 'bytes' bytes, header
+    /// and EOF byte included, before any reallocation is needed.
+    pub fn with_capacity(bytes: usize) -> Listpack {
+        let mut lp = Listpack::new();
+        let additional = bytes.saturating_sub(get_total_bytes(lp.0) as usize);
+        lp.reserve(additional);
+        lp
+    }
+
+    /// Size in bytes of the allocation holding the listpack. This is never
+    /// less than the serialized size.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.1.capacity()
+    }
+
+    /// Reserve room for at least 'additional' more bytes so that mutations
+    /// within the capacity don't reallocate.
+    #[inline]
+    pub fn reserve(&mut self, additional: usize) {
+        let lp = self.1.reserve(self.0, additional);
+        if !lp.is_null() {
+            self.0 = lp;
+        }
+    }
+
+    /// Release the spare capacity.
+    #[inline]
+    pub fn shrink_to_fit(&mut self) {
+        let lp = self.1.shrink_to_fit(self.0);
+        if !lp.is_null() {
+            self.0 = lp;
+        }
+    }
+}
+
+impl<A: Allocator> Listpack<A> {
+    /// Create an empty listpack allocated through 'allocator'.
+    pub fn new_in(allocator: A) -> Listpack<A> {
+        Listpack(new(&allocator), allocator)
     }
 
     #[inline]
-    pub fn insert<V: Into<Value>>(
+    pub fn insert<V: ToValue>(
         &mut self,
         v: V,
         place: Placement,
         target: element,
     ) -> Option<element> {
-        match insert(ALLOCATOR, self.0, v.into(), place, target) {
+        match insert(&self.1, self.0, v.to_value(), place, target) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -35,7 +113,7 @@ impl Listpack {
         place: Placement,
         target: element,
     ) -> Option<element> {
-        match insert(ALLOCATOR, self.0, v, place, target) {
+        match insert(&self.1, self.0, v, place, target) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -53,7 +131,7 @@ impl Listpack {
     ) -> Option<element>
         where
             T: Int {
-        match insert_int(ALLOCATOR, self.0, v, place, target) {
+        match insert_int(&self.1, self.0, v, place, target) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -71,7 +149,7 @@ impl Listpack {
     ) -> Option<element>
         where
             T: Int {
-        match insert_signed_int(ALLOCATOR, self.0, v, place, target) {
+        match insert_signed_int(&self.1, self.0, v, place, target) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -89,7 +167,7 @@ impl Listpack {
     ) -> Option<element>
         where
             T: Str {
-        match insert_string(ALLOCATOR, self.0, v, place, target) {
+        match insert_string(&self.1, self.0, v, place, target) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -99,12 +177,12 @@ impl Listpack {
     }
 
     #[inline]
-    pub fn replace<V: Into<Value>>(
+    pub fn replace<V: ToValue>(
         &mut self,
         p: element,
         v: V
     ) -> Option<element> {
-        match replace(ALLOCATOR, self.0, p, v.into()) {
+        match replace(&self.1, self.0, p, v.to_value()) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -119,7 +197,7 @@ impl Listpack {
         p: element,
         v: Value
     ) -> Option<element> {
-        match replace(ALLOCATOR, self.0, p, v) {
+        match replace(&self.1, self.0, p, v) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -136,7 +214,7 @@ impl Listpack {
     ) -> Option<element>
         where
             T: Int {
-        match replace_int(ALLOCATOR, self.0, p, v) {
+        match replace_int(&self.1, self.0, p, v) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -153,7 +231,7 @@ impl Listpack {
     ) -> Option<element>
         where
             T: Int {
-        match replace_signed_int(ALLOCATOR, self.0, p, v) {
+        match replace_signed_int(&self.1, self.0, p, v) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -170,7 +248,7 @@ impl Listpack {
     ) -> Option<element>
         where
             T: Str {
-        match replace_string(ALLOCATOR, self.0, p, v) {
+        match replace_string(&self.1, self.0, p, v) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -184,7 +262,7 @@ impl Listpack {
         &mut self,
         v: Value
     ) -> bool {
-        match append(ALLOCATOR, self.0, v) {
+        match append(&self.1, self.0, v) {
             Some(lp) => {
                 self.0 = lp;
                 true
@@ -194,11 +272,11 @@ impl Listpack {
     }
 
     #[inline]
-    pub fn append<V: Into<Value>>(
+    pub fn append<V: ToValue>(
         &mut self,
         v: V
     ) -> bool {
-        match append(ALLOCATOR, self.0, v.into()) {
+        match append(&self.1, self.0, v.to_value()) {
             Some(lp) => {
                 self.0 = lp;
                 true
@@ -214,7 +292,7 @@ impl Listpack {
     ) -> bool
         where
             T: Int {
-        match append_int(ALLOCATOR, self.0, v) {
+        match append_int(&self.1, self.0, v) {
             Some(lp) => {
                 self.0 = lp;
                 true
@@ -230,7 +308,7 @@ impl Listpack {
     ) -> bool
         where
             T: Int {
-        match append_signed_int(ALLOCATOR, self.0, v) {
+        match append_signed_int(&self.1, self.0, v) {
             Some(lp) => {
                 self.0 = lp;
                 true
@@ -246,7 +324,28 @@ impl Listpack {
     ) -> bool
         where
             T: Str {
-        match append_string(ALLOCATOR, self.0, v) {
+        match append_string(&self.1, self.0, v) {
+            Some(lp) => {
+                self.0 = lp;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Append all the values growing the allocation only once. Returns false,
+    /// leaving the listpack untouched, if the values don't fit. `Extend`
+    /// panics instead.
+    #[inline]
+    pub fn try_extend<I, V>(
+        &mut self,
+        values: I
+    ) -> bool
+        where
+            I: IntoIterator<Item = V>,
+            V: ToValue {
+        let values: Vec<V> = values.into_iter().collect();
+        match append_many(&self.1, self.0, values.iter().map(ToValue::to_value)) {
             Some(lp) => {
                 self.0 = lp;
                 true
@@ -260,7 +359,80 @@ impl Listpack {
         &mut self,
         p: element
     ) -> Option<element> {
-        match delete(ALLOCATOR, self.0, p) {
+        match delete(&self.1, self.0, p) {
+            Some((lp, ele)) => {
+                self.0 = lp;
+                Some(ele)
+            }
+            None => None
+        }
+    }
+
+    #[inline]
+    pub fn delete_range(
+        &mut self,
+        index: isize,
+        count: u32
+    ) -> bool {
+        match delete_range(&self.1, self.0, index, count) {
+            Some(lp) => {
+                self.0 = lp;
+                true
+            }
+            None => false
+        }
+    }
+
+    #[inline]
+    pub fn delete_range_with_entry(
+        &mut self,
+        p: element,
+        count: u32
+    ) -> Option<element> {
+        match delete_range_with_entry(&self.1, self.0, p, count) {
+            Some((lp, ele)) => {
+                self.0 = lp;
+                Some(ele)
+            }
+            None => None
+        }
+    }
+
+    #[inline]
+    pub fn batch_delete(
+        &mut self,
+        ps: &[element]
+    ) -> bool {
+        match batch_delete(&self.1, self.0, ps) {
+            Some(lp) => {
+                self.0 = lp;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Re-encode every element as compactly as possible and return the
+    /// number of bytes saved. See `raw::optimize`.
+    #[inline]
+    pub fn optimize(&mut self) -> usize {
+        let (lp, saved) = optimize(&self.1, self.0);
+        self.0 = lp;
+        saved
+    }
+
+    #[inline]
+    pub fn insert_many<I, V>(
+        &mut self,
+        values: I,
+        place: Placement,
+        target: element
+    ) -> Option<element>
+        where
+            I: IntoIterator<Item = V>,
+            V: ToValue {
+        let values: Vec<V> = values.into_iter().collect();
+        match insert_many(&self.1, self.0, values.iter().map(ToValue::to_value), place, target) {
             Some((lp, ele)) => {
                 self.0 = lp;
                 Some(ele)
@@ -368,15 +540,179 @@ impl Listpack {
         get_bytes(ele)
     }
 
+    /// Find the first element equal to 's' scanning from the head, skipping
+    /// 'skip' elements after every comparison. See `raw::find`.
+    #[inline]
+    pub fn find(&self, s: &[u8], skip: u32) -> Option<element> {
+        find(self.0, first(self.0)?, s, skip)
+    }
+
+    /// Memory usage per encoding. See `raw::stats`.
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        stats(self.0)
+    }
+
     #[inline(always)]
     pub fn seek() {
 
     }
 }
 
-impl Drop for Listpack {
+impl<A: Allocator> Drop for Listpack<A> {
     fn drop(&mut self) {
-        ALLOCATOR.dealloc(self.0);
+        self.1.dealloc(self.0, total_bytes(&self.1, self.0));
+    }
+}
+
+impl Listpack {
+    /// Build a listpack from its serialized bytes, header and EOF byte
+    /// included. The bytes are validated with `raw::validate` and copied
+    /// into a new allocation.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Listpack, InvalidListpack> {
+        Listpack::from_bytes_in(bytes, CapacityAllocator::new())
+    }
+
+    /// Build a listpack from its serialized bytes, taking over the buffer
+    /// of 'bytes' instead of copying it. The bytes are validated with
+    /// `raw::validate` and the spare capacity of the vector becomes the
+    /// capacity of the listpack.
+    pub fn from_vec(bytes: Vec<u8>) -> Result<Listpack, InvalidListpack> {
+        if !validate(&bytes) {
+            return Err(InvalidListpack);
+        }
+        let mut bytes = std::mem::ManuallyDrop::new(bytes);
+        let allocator = CapacityAllocator::with_capacity(bytes.capacity());
+        Ok(Listpack(bytes.as_mut_ptr(), allocator))
+    }
+
+    /// Give up the allocation as a boxed slice of the serialized bytes. The
+    /// spare capacity is released first.
+    pub fn into_boxed_slice(mut self) -> Box<[u8]> {
+        self.shrink_to_fit();
+        if self.capacity() != self.as_bytes().len() {
+            // Shrinking failed, so the allocation doesn't match the slice.
+            return self.as_bytes().into();
+        }
+        let lp = std::mem::ManuallyDrop::new(self);
+        unsafe {
+            Box::from_raw(std::ptr::slice_from_raw_parts_mut(lp.0, lp.capacity()))
+        }
+    }
+}
+
+impl<A: Allocator> Listpack<A> {
+    /// Like `from_bytes` but the copy is allocated through 'allocator'.
+    pub fn from_bytes_in(bytes: &[u8], allocator: A) -> Result<Listpack<A>, InvalidListpack> {
+        if !validate(bytes) {
+            return Err(InvalidListpack);
+        }
+        Ok(Listpack::copy_in(bytes, allocator))
+    }
+
+    /// The serialized listpack, header and EOF byte included.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.0, total_bytes(&self.1, self.0))
+        }
+    }
+
+    /// Copy serialized bytes known to be valid into a new allocation made by
+    /// 'allocator' and sized exactly to fit them.
+    fn copy_in(bytes: &[u8], allocator: A) -> Listpack<A> {
+        let lp = allocator.alloc(bytes.len());
+        if lp.is_null() {
+            std::alloc::handle_alloc_error(unsafe {
+                std::alloc::Layout::from_size_align_unchecked(
+                    bytes.len(),
+                    std::mem::align_of::<u8>(),
+                )
+            });
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), lp, bytes.len());
+        }
+        Listpack(lp, allocator)
+    }
+}
+
+/// Error returned when building a `Listpack` from bytes that aren't a valid
+/// serialized listpack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidListpack;
+
+impl std::fmt::Display for InvalidListpack {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("invalid listpack")
+    }
+}
+
+impl std::error::Error for InvalidListpack {}
+
+impl<A: Allocator + Clone> Clone for Listpack<A> {
+    /// Copy the serialized bytes into a new allocation, made by a clone of
+    /// the allocator, sized exactly to fit them. The spare capacity of the
+    /// original isn't carried over.
+    fn clone(&self) -> Self {
+        Listpack::copy_in(self.as_bytes(), self.1.clone())
+    }
+}
+
+impl<A: Allocator, B: Allocator> PartialEq<Listpack<B>> for Listpack<A> {
+    /// Two listpacks are equal when their serialized bytes are. The same
+    /// values in different encodings don't compare equal.
+    #[inline]
+    fn eq(&self, other: &Listpack<B>) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<A: Allocator> Eq for Listpack<A> {}
+
+impl<A: Allocator> std::hash::Hash for Listpack<A> {
+    #[inline]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state)
+    }
+}
+
+impl<A: Allocator> std::fmt::Debug for Listpack<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut list = f.debug_list();
+        let mut ele = first(self.0);
+        while let Some(p) = ele {
+            list.entry(&get(p));
+            ele = next(self.0, p);
+        }
+        list.finish()
+    }
+}
+
+// A Listpack owns its allocation exclusively: the pointer is never shared
+// with another Listpack and the allocator is only called to allocate from
+// `&mut self` methods. Methods taking `&self` only read the bytes, but may
+// use the allocator through a shared reference too, e.g. `clone()` and
+// `capacity()`, so sharing the listpack between threads needs a `Sync`
+// allocator.
+unsafe impl<A: Allocator + Send> Send for Listpack<A> {}
+unsafe impl<A: Allocator + Sync> Sync for Listpack<A> {}
+
+/// Panics if the allocator fails, like `Vec` does. Use `try_extend()` to
+/// handle it.
+impl<A: Allocator, V: ToValue> Extend<V> for Listpack<A> {
+    fn extend<I: IntoIterator<Item = V>>(&mut self, iter: I) {
+        if !self.try_extend(iter) {
+            panic!("listpack allocation failed");
+        }
+    }
+}
+
+impl<A: Allocator + Default, V: ToValue> std::iter::FromIterator<V> for Listpack<A> {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut lp = Listpack::new_in(A::default());
+        Extend::extend(&mut lp, iter);
+        lp
     }
 }
 
@@ -410,6 +746,240 @@ mod tests {
             true
         });
     }
-}
 
+    #[test]
+    fn find() {
+        let mut lp = Listpack::new();
+
+        for i in 0..10 {
+            lp.append(&format!("field:{}", i));
+            lp.append(i);
+        }
 
+        let ele = lp.find(b"field:7", 1).unwrap();
+        assert_eq!(lp.get_str(ele), "field:7");
+        assert_eq!(lp.get_int(next(lp.0, ele).unwrap()), 7);
+
+        // Values are skipped.
+        assert!(lp.find(b"7", 1).is_none());
+        assert!(lp.find(b"7", 0).is_some());
+    }
+
+    #[test]
+    fn extend() {
+        let mut lp: Listpack = (0..100).collect();
+        assert_eq!(length(lp.0), 100);
+
+        assert!(lp.try_extend(vec!["a", "b", "c"]));
+        lp.extend(100..200);
+        assert_eq!(length(lp.0), 203);
+
+        assert_eq!(lp.get_int(first(lp.0).unwrap()), 0);
+        assert_eq!(lp.get_str(seek(lp.0, 101).unwrap()), "b");
+        assert_eq!(lp.get_int(last(lp.0).unwrap()), 199);
+    }
+
+    #[test]
+    fn owned_values() {
+        let strings: Vec<String> = (0..50).map(|i| format!("string {}", i)).collect();
+        let lp: Listpack = strings.clone().into_iter().collect();
+        let mut p = first(lp.0);
+        for s in &strings {
+            assert_eq!(lp.get_str(p.unwrap()), s);
+            p = next(lp.0, p.unwrap());
+        }
+        assert!(p.is_none());
+
+        let mut lp = Listpack::new();
+        assert!(lp.try_extend(vec![b"bytes".to_vec(), vec![0, 1, 2]]));
+        lp.extend(vec![String::from("more")]);
+        assert!(lp.append(String::from("appended")));
+        assert!(lp.append(u128::max_value() - 1));
+        assert_eq!(lp.get_bytes(first(lp.0).unwrap()), b"bytes");
+        assert_eq!(lp.get_str(seek(lp.0, 2).unwrap()), "more");
+        assert_eq!(lp.get_str(seek(lp.0, 3).unwrap()), "appended");
+        assert_eq!(lp.get_bytes(last(lp.0).unwrap()), &(u128::max_value() - 1).to_ne_bytes()[..]);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut lp = Listpack::with_capacity(1024);
+        assert_eq!(lp.capacity(), 1024);
+
+        let ptr = lp.0;
+        for i in 0..100 {
+            lp.append(i);
+        }
+        assert_eq!(lp.0, ptr);
+        assert_eq!(lp.capacity(), 1024);
+        assert_eq!(get_total_bytes(lp.0), 6 + 100 * 2 + 1);
+
+        lp.delete_range(0, 50);
+        assert_eq!(lp.capacity(), 1024);
+
+        lp.shrink_to_fit();
+        assert_eq!(lp.capacity(), 6 + 50 * 2 + 1);
+        assert_eq!(lp.get_int(first(lp.0).unwrap()), 50);
+
+        lp.reserve(10);
+        assert_eq!(lp.capacity(), 6 + 50 * 2 + 1 + 10);
+        lp.append(1);
+        lp.append(2);
+        assert_eq!(lp.capacity(), 6 + 50 * 2 + 1 + 10);
+        assert_eq!(lp.get_int(last(lp.0).unwrap()), 2);
+    }
+
+    #[test]
+    fn clone_eq_hash() {
+        use std::collections::HashSet;
+
+        let mut lp: Listpack = (0..10).collect();
+        lp.append("ten");
+
+        let mut copy = lp.clone();
+        assert_eq!(copy, lp);
+        assert_ne!(copy.0, lp.0);
+        assert_eq!(copy.capacity(), get_total_bytes(lp.0) as usize);
+
+        let mut set = HashSet::new();
+        set.insert(lp.clone());
+        assert!(set.contains(&copy));
+
+        copy.append(11);
+        assert_ne!(copy, lp);
+        assert!(!set.contains(&copy));
+        assert_eq!(length(lp.0), 11);
+    }
+
+    #[test]
+    fn debug() {
+        let mut lp = Listpack::new();
+        lp.append(-1);
+        lp.append("hi");
+        assert_eq!(format!("{:?}", lp), r#"[Int(-1), String("hi")]"#);
+        assert_eq!(format!("{:?}", Listpack::new()), "[]");
+    }
+
+    #[test]
+    fn send_sync() {
+        use std::sync::Arc;
+        use std::thread;
+
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<Listpack>();
+        assert_sync::<Listpack<DefaultAllocator>>();
+
+        let lp: Listpack = (0..100).collect();
+        let mut lp = thread::spawn(move || {
+            let mut lp = lp;
+            lp.append("moved");
+            lp
+        }).join().unwrap();
+        assert_eq!(length(lp.0), 101);
+        assert_eq!(lp.get_str(last(lp.0).unwrap()), "moved");
+
+        lp.append(100);
+        let shared = Arc::new(lp);
+        let handles: Vec<_> = (0..4).map(|_| {
+            let lp = shared.clone();
+            thread::spawn(move || {
+                lp.find(b"moved", 0).map(|p| lp.get_str(p).to_owned())
+            })
+        }).collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().as_ref().map(|s| &s[..]), Some("moved"));
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        let lp: Listpack = vec!["a", "bb"].into_iter().collect();
+        let bytes = lp.as_bytes().to_vec();
+        assert_eq!(bytes.len(), get_total_bytes(lp.0) as usize);
+
+        let copy = Listpack::from_bytes(&bytes).unwrap();
+        assert_eq!(copy, lp);
+
+        let mut spare = Vec::with_capacity(64);
+        spare.extend_from_slice(&bytes);
+        let ptr = spare.as_ptr();
+        let mut adopted = Listpack::from_vec(spare).unwrap();
+        assert_eq!(adopted.0 as *const u8, ptr);
+        assert_eq!(adopted.capacity(), 64);
+        adopted.append(7);
+        assert_eq!(adopted.0 as *const u8, ptr);
+
+        let expected = adopted.clone();
+        let boxed = adopted.into_boxed_slice();
+        assert_eq!(boxed.len(), bytes.len() + 2);
+        assert_eq!(&boxed[..], expected.as_bytes());
+        assert_eq!(Listpack::from_vec(boxed.into_vec()).unwrap(), expected);
+
+        let mut corrupt = bytes.clone();
+        corrupt[0] += 1;
+        assert_eq!(Listpack::from_bytes(&corrupt), Err(InvalidListpack));
+        assert_eq!(Listpack::from_vec(corrupt).err(), Some(InvalidListpack));
+        assert!(Listpack::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn optimize() {
+        let mut lp: Listpack = vec!["1", "two", "-300"].into_iter().collect();
+        let stats = lp.stats();
+        assert_eq!(stats.int_strings, 2);
+        assert_eq!(lp.optimize(), stats.int_string_savings);
+        assert_eq!(lp.as_bytes().len(), stats.total_bytes - stats.int_string_savings);
+        assert_eq!(lp.get_int(first(lp.0).unwrap()), 1);
+        assert_eq!(lp.get_int(last(lp.0).unwrap()), -300);
+        assert_eq!(lp.optimize(), 0);
+    }
+
+    #[test]
+    fn new_in() {
+        use std::cell::Cell;
+
+        struct Counting {
+            allocs: Cell<usize>,
+            deallocs: Cell<usize>,
+        }
+
+        impl Allocator for Counting {
+            fn has_header(&self) -> bool {
+                true
+            }
+
+            fn alloc(&self, size: usize) -> listpack {
+                self.allocs.set(self.allocs.get() + 1);
+                DefaultAllocator.alloc(size)
+            }
+
+            fn realloc(&self, lp: listpack, old_size: usize, new_size: usize) -> listpack {
+                DefaultAllocator.realloc(lp, old_size, new_size)
+            }
+
+            fn dealloc(&self, lp: listpack, size: usize) {
+                self.deallocs.set(self.deallocs.get() + 1);
+                DefaultAllocator.dealloc(lp, size)
+            }
+        }
+
+        let counting = Counting { allocs: Cell::new(0), deallocs: Cell::new(0) };
+        {
+            let mut a = Listpack::new_in(&counting);
+            let mut b = Listpack::new_in(&counting);
+            a.append("shared");
+            b.append("shared");
+            assert_eq!(a, b);
+            assert_eq!(a.clone(), b);
+            assert_eq!(counting.allocs.get(), 3);
+        }
+        assert_eq!(counting.deallocs.get(), 3);
+
+        let mut lp = Listpack::new_in(DefaultAllocator);
+        lp.append(1);
+        lp.append("two");
+        let default: Listpack = vec![Value::Int(1), "two".into()].into_iter().collect();
+        assert_eq!(lp, default);
+        assert_eq!(format!("{:?}", lp), r#"[Int(1), String("two")]"#);
+    }
+}
@@ -0,0 +1,256 @@
+//! Port of the LZF compressor bundled with Redis (liblzf 3.6 built with
+//! `HLOG` 16 and `VERY_FAST`), so compressed quicklist nodes are byte for
+//! byte what Redis produces and Redis payloads can be decompressed.
+
+use std;
+
+const HLOG: u32 = 16;
+const HSIZE: usize = 1 << HLOG;
+
+const MAX_LIT: usize = 1 << 5;
+const MAX_OFF: usize = 1 << 13;
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+
+#[inline(always)]
+fn frst(input: &[u8], ip: usize) -> u32 {
+    ((input[ip] as u32) << 8) | input[ip + 1] as u32
+}
+
+#[inline(always)]
+fn next(v: u32, input: &[u8], ip: usize) -> u32 {
+    (v << 8) | input[ip + 2] as u32
+}
+
+#[inline(always)]
+fn idx(h: u32) -> usize {
+    ((h >> (3 * 8 - HLOG)).wrapping_sub(h.wrapping_mul(5)) as usize) & (HSIZE - 1)
+}
+
+/// Compress 'input' into at most 'out_len' bytes. Returns None if the
+/// output doesn't fit, like `lzf_compress()` returning 0.
+pub fn compress(input: &[u8], out_len: usize) -> Option<Vec<u8>> {
+    let in_len = input.len();
+    if in_len == 0 || out_len == 0 {
+        return None;
+    }
+
+    // Positions in 'input'. The slot of the first byte doubles as "empty",
+    // which is why Redis never matches against offset 0 either.
+    let mut htab = vec![0u32; HSIZE];
+    let mut out = vec![0u8; out_len];
+    let mut ip = 0usize;
+    let mut op = 1usize; // start run
+    let mut lit = 0usize;
+
+    let mut hval = if in_len >= 2 { frst(input, ip) } else { 0 };
+    while ip + 2 < in_len {
+        hval = next(hval, input, ip);
+        let slot = idx(hval);
+        let r = htab[slot] as usize;
+        htab[slot] = ip as u32;
+
+        let off = ip.wrapping_sub(r).wrapping_sub(1);
+        if off < MAX_OFF
+            && r > 0
+            && input[r + 2] == input[ip + 2]
+            && input[r] == input[ip]
+            && input[r + 1] == input[ip + 1] {
+            // Match found at 'r'.
+            let mut len = 2;
+            let maxlen = std::cmp::min(in_len - ip - len, MAX_REF);
+
+            if op + 3 + 1 >= out_len && op - (lit == 0) as usize + 3 + 1 >= out_len {
+                return None;
+            }
+
+            out[op - lit - 1] = (lit as u8).wrapping_sub(1); // stop run
+            op -= (lit == 0) as usize; // undo run if length is zero
+
+            // Same probing order as the unrolled C loop.
+            let mut matched = false;
+            if maxlen > 16 {
+                matched = true;
+                for _ in 0..16 {
+                    len += 1;
+                    if input[r + len] != input[ip + len] {
+                        matched = false;
+                        break;
+                    }
+                }
+            }
+            if matched || maxlen <= 16 {
+                loop {
+                    len += 1;
+                    if !(len < maxlen && input[r + len] == input[ip + len]) {
+                        break;
+                    }
+                }
+            }
+
+            len -= 2; // len is now #octets - 1
+            ip += 1;
+
+            if len < 7 {
+                out[op] = ((off >> 8) + (len << 5)) as u8;
+                op += 1;
+            } else {
+                out[op] = ((off >> 8) + (7 << 5)) as u8;
+                out[op + 1] = (len - 7) as u8;
+                op += 2;
+            }
+            out[op] = off as u8;
+            op += 1;
+
+            lit = 0;
+            op += 1; // start run
+
+            ip += len + 1;
+            if ip + 2 >= in_len {
+                break;
+            }
+
+            // Hash the last two positions of the match.
+            ip -= 2;
+            hval = frst(input, ip);
+            hval = next(hval, input, ip);
+            htab[idx(hval)] = ip as u32;
+            ip += 1;
+            hval = next(hval, input, ip);
+            htab[idx(hval)] = ip as u32;
+            ip += 1;
+        } else {
+            // One more literal byte we must copy.
+            if op >= out_len {
+                return None;
+            }
+            lit += 1;
+            out[op] = input[ip];
+            op += 1;
+            ip += 1;
+
+            if lit == MAX_LIT {
+                out[op - lit - 1] = (lit - 1) as u8; // stop run
+                lit = 0;
+                op += 1; // start run
+            }
+        }
+    }
+
+    // At most 3 bytes can be missing here.
+    if op + 3 > out_len {
+        return None;
+    }
+
+    while ip < in_len {
+        lit += 1;
+        out[op] = input[ip];
+        op += 1;
+        ip += 1;
+
+        if lit == MAX_LIT {
+            out[op - lit - 1] = (lit - 1) as u8;
+            lit = 0;
+            op += 1;
+        }
+    }
+
+    out[op - lit - 1] = (lit as u8).wrapping_sub(1); // end run
+    op -= (lit == 0) as usize;
+
+    out.truncate(op);
+    Some(out)
+}
+
+/// Largest size 'len' compressed bytes can expand to: every 3 byte back
+/// reference copies at most 264 bytes.
+#[inline]
+pub fn max_decompressed_len(len: usize) -> usize {
+    len.saturating_mul(88)
+}
+
+/// Decompress 'input', which must expand to at most 'out_len' bytes.
+/// Returns None on corrupt input or if the output doesn't fit.
+pub fn decompress(input: &[u8], out_len: usize) -> Option<Vec<u8>> {
+    // 'out_len' may come from untrusted input.
+    let mut out = Vec::with_capacity(out_len.min(max_decompressed_len(input.len())));
+    let mut ip = 0usize;
+
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+
+        if ctrl < (1 << 5) {
+            // Literal run.
+            let len = ctrl + 1;
+            if out.len() + len > out_len || ip + len > input.len() {
+                return None;
+            }
+            out.extend_from_slice(&input[ip..ip + len]);
+            ip += len;
+        } else {
+            // Back reference.
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip)? as usize;
+                ip += 1;
+            }
+            let distance = ((ctrl & 0x1f) << 8) + *input.get(ip)? as usize + 1;
+            ip += 1;
+
+            if out.len() + len + 2 > out_len || distance > out.len() {
+                return None;
+            }
+            // Byte by byte since the reference may overlap the output.
+            let start = out.len() - distance;
+            for i in 0..len + 2 {
+                let b = out[start + i];
+                out.push(b);
+            }
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_output() {
+        let compressed = compress(b"abcabcabcabc", 12).unwrap();
+        assert_eq!(compressed, vec![3, b'a', b'b', b'c', b'a', 0x80, 2, 1, b'b', b'c']);
+        assert_eq!(decompress(&compressed, 12).unwrap(), b"abcabcabcabc".to_vec());
+
+        let long = [2, b'a', b'b', b'c', 0xe0, 0, 2];
+        assert_eq!(decompress(&long, 12).unwrap(), b"abcabcabcabc".to_vec());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut input = Vec::new();
+        for i in 0..2000u32 {
+            input.extend_from_slice(format!("entry:{}:{}", i % 37, i * 7).as_bytes());
+        }
+        let compressed = compress(&input, input.len()).unwrap();
+        assert!(compressed.len() < input.len() / 2);
+        assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+
+        // Incompressible data doesn't fit in its own size.
+        let noise: Vec<u8> = (0..64u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        assert!(compress(&noise, noise.len()).is_none());
+        assert!(compress(&noise, noise.len() + 8).is_some());
+
+        for len in 1..4 {
+            let c = compress(&input[..len], len + 4).unwrap();
+            assert_eq!(decompress(&c, len).unwrap(), &input[..len]);
+        }
+    }
+
+    #[test]
+    fn corrupt_input() {
+        assert!(decompress(&[5, b'a'], 10).is_none());
+        assert!(decompress(&[0x20, 0], 10).is_none());
+        assert!(decompress(&[0, b'a', 0xe0], 10).is_none());
+        assert!(decompress(&[2, b'a', b'b', b'c'], 2).is_none());
+    }
+}
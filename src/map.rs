@@ -0,0 +1,297 @@
+use ::raw::*;
+use ::Listpack;
+
+/// Default maximum number of fields of a listpack encoded hash in Redis
+/// (`hash-max-listpack-entries`).
+pub const DEFAULT_MAX_ENTRIES: usize = 128;
+/// Default maximum size of a field or value of a listpack encoded hash in
+/// Redis (`hash-max-listpack-value`).
+pub const DEFAULT_MAX_VALUE: usize = 64;
+
+/// Small field/value hash encoded the way Redis encodes small hashes: a
+/// listpack of alternating field and value entries. Lookups are linear scans,
+/// so once the map grows past its limits callers are expected to move the
+/// entries into a real hash table (see `needs_conversion()`).
+pub struct ListpackMap<A: Allocator = CapacityAllocator> {
+    lp: Listpack<A>,
+    max_entries: usize,
+    max_value: usize,
+    oversized: bool,
+}
+
+impl ListpackMap {
+    pub fn new() -> ListpackMap {
+        ListpackMap::with_limits(DEFAULT_MAX_ENTRIES, DEFAULT_MAX_VALUE)
+    }
+
+    /// Create an empty map with custom conversion limits.
+    pub fn with_limits(max_entries: usize, max_value: usize) -> ListpackMap {
+        ListpackMap {
+            lp: Listpack::new(),
+            max_entries,
+            max_value,
+            oversized: false,
+        }
+    }
+}
+
+impl<A: Allocator> ListpackMap<A> {
+    pub fn new_in(allocator: A) -> ListpackMap<A> {
+        ListpackMap::with_limits_in(DEFAULT_MAX_ENTRIES, DEFAULT_MAX_VALUE, allocator)
+    }
+
+    pub fn with_limits_in(max_entries: usize, max_value: usize, allocator: A) -> ListpackMap<A> {
+        ListpackMap {
+            lp: Listpack::new_in(allocator),
+            max_entries,
+            max_value,
+            oversized: false,
+        }
+    }
+
+    /// Number of field/value pairs.
+    #[inline]
+    pub fn len(&self) -> usize {
+        length(self.lp.0) as usize / 2
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        first(self.lp.0).is_none()
+    }
+
+    #[inline]
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    #[inline]
+    pub fn max_value(&self) -> usize {
+        self.max_value
+    }
+
+    /// Returns true once the map holds more than `max_entries` pairs or a
+    /// field or value larger than `max_value` bytes was inserted. Like Redis
+    /// the hint is never reset, since such a map should be converted to a
+    /// real hash table.
+    #[inline]
+    pub fn needs_conversion(&self) -> bool {
+        self.oversized || self.len() > self.max_entries
+    }
+
+    /// Find the element holding 'key'. Only fields are compared.
+    #[inline]
+    fn find_field(&self, key: &[u8]) -> Option<element> {
+        self.lp.find(key, 1)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Value> {
+        let field = self.find_field(key)?;
+        next(self.lp.0, field).map(get)
+    }
+
+    #[inline]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.find_field(key).is_some()
+    }
+
+    /// Set the value of 'key'. An existing value is replaced in place,
+    /// otherwise the pair is appended. Strings that are the canonical
+    /// representation of an integer are stored integer encoded, as Redis
+    /// does. Returns whether the key was not present, or None, leaving the
+    /// map untouched, if the allocator failed.
+    pub fn insert<V: ToValue>(&mut self, key: &[u8], value: V) -> Option<bool> {
+        // Borrows 'value', which lives until the end of the call.
+        let value = match value.to_value() {
+            Value::Int(v) => Value::Int(v),
+            s @ Value::String(_, _) => Value::compact(s.as_bytes()),
+        };
+
+        let added = match self.find_field(key) {
+            Some(field) => {
+                let ele = next(self.lp.0, field)?;
+                self.lp.replace_val(ele, value)?;
+                false
+            }
+            None => {
+                if !self.lp.try_extend(vec![Value::compact(key), value]) {
+                    return None;
+                }
+                true
+            }
+        };
+
+        if key.len() > self.max_value {
+            self.oversized = true;
+        }
+        if let Value::String(_, len) = value {
+            if len as usize > self.max_value {
+                self.oversized = true;
+            }
+        }
+        Some(added)
+    }
+
+    /// Remove 'key' and its value. Returns true if the key was present.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        match self.find_field(key) {
+            Some(field) => self.lp.delete_range_with_entry(field, 2).is_some(),
+            None => false
+        }
+    }
+
+    /// Iterate the field/value pairs in insertion order.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter {
+            lp: &self.lp,
+            ele: first(self.lp.0),
+        }
+    }
+
+    #[inline]
+    pub fn keys<'a>(&'a self) -> impl Iterator<Item = Value> + 'a {
+        self.iter().map(|(k, _)| k)
+    }
+
+    #[inline]
+    pub fn values<'a>(&'a self) -> impl Iterator<Item = Value> + 'a {
+        self.iter().map(|(_, v)| v)
+    }
+
+    #[inline]
+    pub fn as_listpack(&self) -> &Listpack<A> {
+        &self.lp
+    }
+
+    #[inline]
+    pub fn into_listpack(self) -> Listpack<A> {
+        self.lp
+    }
+}
+
+impl Default for ListpackMap {
+    fn default() -> Self {
+        ListpackMap::new()
+    }
+}
+
+/// Iterator over the field/value pairs of a `ListpackMap`.
+pub struct Iter<'a, A: Allocator = CapacityAllocator> {
+    lp: &'a Listpack<A>,
+    ele: Option<element>,
+}
+
+impl<'a, A: Allocator> Iterator for Iter<'a, A> {
+    type Item = (Value, Value);
+
+    fn next(&mut self) -> Option<(Value, Value)> {
+        let field = self.ele?;
+        let value = next(self.lp.0, field)?;
+        self.ele = next(self.lp.0, value);
+        Some((get(field), get(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracking::TrackingAllocator;
+
+    #[test]
+    fn insert_get_remove() {
+        let mut map = ListpackMap::new();
+        assert!(map.is_empty());
+
+        assert_eq!(map.insert(b"name", "listpack"), Some(true));
+        assert_eq!(map.insert(b"visits", 10), Some(true));
+        assert_eq!(map.insert(b"42", "answer"), Some(true));
+        assert_eq!(map.len(), 3);
+
+        assert!(map.get(b"name").unwrap() == "listpack".into());
+        assert_eq!(map.get(b"visits").unwrap().as_int(), Some(10));
+        assert!(map.get(b"42").unwrap() == "answer".into());
+        assert!(map.get(b"10").is_none());
+        assert!(map.get(b"listpack").is_none());
+
+        // Replace in place with values of different sizes.
+        assert_eq!(map.insert(b"visits", "a much longer value than before"), Some(false));
+        assert_eq!(map.insert(b"name", 1), Some(false));
+        assert!(map.get(b"visits").unwrap() == "a much longer value than before".into());
+        assert_eq!(map.get(b"name").unwrap().as_int(), Some(1));
+        assert_eq!(map.len(), 3);
+
+        assert!(map.remove(b"visits"));
+        assert!(!map.remove(b"visits"));
+        assert!(!map.contains_key(b"visits"));
+        assert!(map.contains_key(b"42"));
+        assert_eq!(map.len(), 2);
+
+        let keys: Vec<String> = map.keys()
+            .map(|k| match k {
+                Value::Int(v) => v.to_string(),
+                s => s.as_str().to_string()
+            })
+            .collect();
+        assert_eq!(keys, vec!["name", "42"]);
+    }
+
+    #[test]
+    fn insert_owned() {
+        let mut map = ListpackMap::new();
+        assert_eq!(map.insert(b"name", String::from("listpack")), Some(true));
+        assert_eq!(map.insert(b"bytes", vec![0u8, 1, 2]), Some(true));
+        assert_eq!(map.insert(b"count", 7.to_string()), Some(true));
+
+        assert!(map.get(b"name").unwrap() == "listpack".into());
+        assert_eq!(map.get(b"bytes").unwrap().as_bytes(), &[0, 1, 2]);
+        assert_eq!(map.get(b"count").unwrap().as_int(), Some(7));
+    }
+
+    #[test]
+    fn integer_strings_are_compacted() {
+        let mut map = ListpackMap::new();
+        map.insert(b"1000", "2000");
+
+        let field = first(map.as_listpack().0).unwrap();
+        assert!(is_13bit_int(unsafe { *field }));
+        match map.get(b"1000").unwrap() {
+            Value::Int(v) => assert_eq!(v, 2000),
+            _ => panic!("expected an integer encoded value")
+        }
+    }
+
+    #[test]
+    fn needs_conversion() {
+        let mut map = ListpackMap::with_limits(2, 8);
+        map.insert(b"a", 1);
+        map.insert(b"b", 2);
+        assert!(!map.needs_conversion());
+        map.insert(b"c", 3);
+        assert!(map.needs_conversion());
+
+        let mut map = ListpackMap::with_limits(2, 8);
+        map.insert(b"a", "0123456789");
+        assert!(map.needs_conversion());
+    }
+
+    #[test]
+    fn insert_failure() {
+        let tracker = TrackingAllocator::with_budget(DefaultAllocator, 7 + 5 * 2);
+        let mut map = ListpackMap::with_limits_in(2, 4, &tracker);
+        assert_eq!(map.insert(b"a", 1), Some(true));
+        assert_eq!(map.insert(b"b", 2), Some(true));
+        let before = map.as_listpack().as_bytes().to_vec();
+
+        // Neither a new pair nor a larger value fit, and the map is untouched.
+        assert_eq!(map.insert(b"c", 3), None);
+        assert_eq!(map.insert(b"a", "0123456789"), None);
+        assert_eq!(map.as_listpack().as_bytes(), &before[..]);
+        assert!(!map.needs_conversion());
+        assert_eq!(tracker.rejected(), 2);
+
+        // Replacing with a value of the same size needs no allocation.
+        assert_eq!(map.insert(b"a", 5), Some(false));
+        assert_eq!(map.get(b"a").unwrap().as_int(), Some(5));
+    }
+}
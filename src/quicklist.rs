@@ -0,0 +1,750 @@
+use std;
+use std::cell::{Cell, UnsafeCell};
+use std::collections::VecDeque;
+
+use ::raw::*;
+use ::Listpack;
+use ::lzf;
+
+/// Sizes selected by the negative values of Redis `list-max-listpack-size`.
+const FILL_BYTES: [usize; 5] = [4096, 8192, 16384, 32768, 65536];
+
+/// Bytes of a listpack that aren't entries: the header and the EOF byte.
+const LP_OVERHEAD: usize = HDR_SIZE as usize + 1;
+
+/// Limit on the size of every quicklist node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fill {
+    /// At most this many entries per node.
+    Entries(usize),
+    /// At most this many bytes per node listpack. A single entry larger
+    /// than the limit gets a node of its own.
+    Bytes(usize),
+}
+
+impl Fill {
+    /// Interpret a Redis `list-max-listpack-size` value: positive values are
+    /// a number of entries, -1 to -5 select a node size from 4kb to 64kb.
+    pub fn from_redis(fill: i32) -> Fill {
+        if fill > 0 {
+            Fill::Entries(fill as usize)
+        } else {
+            let level = (-fill).clamp(1, 5) as usize;
+            Fill::Bytes(FILL_BYTES[level - 1])
+        }
+    }
+
+    #[inline]
+    fn fits(&self, count: usize, bytes: usize) -> bool {
+        match *self {
+            Fill::Entries(max) => count <= max,
+            Fill::Bytes(max) => bytes <= max,
+        }
+    }
+}
+
+impl Default for Fill {
+    /// Redis default of 8kb per node.
+    fn default() -> Fill {
+        Fill::Bytes(8192)
+    }
+}
+
+/// Nodes smaller than this are never compressed, like in Redis.
+const MIN_COMPRESS_BYTES: usize = 48;
+/// Compression must save at least this many bytes to be kept.
+const MIN_COMPRESS_IMPROVE: usize = 8;
+
+/// Listpack of a node, either as is or LZF compressed.
+enum Entry {
+    Plain(Listpack),
+    Compressed { data: Vec<u8>, size: usize },
+}
+
+/// A node of the quicklist, holding a listpack and its cached length.
+pub(crate) struct Node {
+    entry: UnsafeCell<Entry>,
+    pub(crate) count: usize,
+    /// Set when a compressed node was decompressed to be read.
+    recompress: Cell<bool>,
+}
+
+impl Node {
+    fn new() -> Node {
+        Node {
+            entry: UnsafeCell::new(Entry::Plain(Listpack::new())),
+            count: 0,
+            recompress: Cell::new(false),
+        }
+    }
+
+    /// The node listpack, decompressed first if needed. A node decompressed
+    /// this way is flagged to be compressed again on the next mutation of
+    /// the list.
+    pub(crate) fn lp(&self) -> &Listpack {
+        unsafe {
+            // Only compressed entries are replaced, and no reference into a
+            // compressed entry ever escapes.
+            let inflated = match *self.entry.get() {
+                Entry::Compressed { ref data, size } => Some(inflate(data, size)),
+                Entry::Plain(_) => None
+            };
+            if let Some(lp) = inflated {
+                *self.entry.get() = Entry::Plain(lp);
+                self.recompress.set(true);
+            }
+            match *self.entry.get() {
+                Entry::Plain(ref lp) => lp,
+                Entry::Compressed { .. } => unreachable!()
+            }
+        }
+    }
+
+    fn lp_mut(&mut self) -> &mut Listpack {
+        self.decompress();
+        match *self.entry.get_mut() {
+            Entry::Plain(ref mut lp) => lp,
+            Entry::Compressed { .. } => unreachable!()
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_compressed(&self) -> bool {
+        match unsafe { &*self.entry.get() } {
+            Entry::Compressed { .. } => true,
+            Entry::Plain(_) => false
+        }
+    }
+
+    /// Compress the listpack unless it's too small or doesn't compress
+    /// well, like `__quicklistCompressNode()`.
+    fn compress(&mut self) -> bool {
+        self.recompress.set(false);
+        let compressed = match *self.entry.get_mut() {
+            Entry::Plain(ref lp) => {
+                let size = get_total_bytes(lp.0) as usize;
+                if size < MIN_COMPRESS_BYTES {
+                    return false;
+                }
+                let bytes = unsafe { std::slice::from_raw_parts(lp.0, size) };
+                match lzf::compress(bytes, size) {
+                    Some(ref data) if data.len() + MIN_COMPRESS_IMPROVE >= size => return false,
+                    Some(data) => Entry::Compressed { data, size },
+                    None => return false
+                }
+            }
+            Entry::Compressed { .. } => return true
+        };
+        *self.entry.get_mut() = compressed;
+        true
+    }
+
+    fn decompress(&mut self) {
+        self.recompress.set(false);
+        let inflated = match *self.entry.get_mut() {
+            Entry::Compressed { ref data, size } => inflate(data, size),
+            Entry::Plain(_) => return
+        };
+        *self.entry.get_mut() = Entry::Plain(inflated);
+    }
+
+    /// Size of the listpack, compressed or not.
+    #[inline]
+    fn bytes(&self) -> usize {
+        match unsafe { &*self.entry.get() } {
+            Entry::Plain(lp) => get_total_bytes(lp.0) as usize,
+            Entry::Compressed { size, .. } => *size
+        }
+    }
+
+    /// Returns true if 'v' can be added without exceeding the fill.
+    #[inline]
+    fn allows(&self, fill: Fill, v: &Value) -> bool {
+        self.count == 0 || fill.fits(self.count + 1, self.bytes() + v.size_for_write() as usize)
+    }
+
+    /// Returns false if the allocator failed.
+    fn push_back(&mut self, v: Value) -> bool {
+        if !self.lp_mut().append_val(v) {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+
+    /// Returns false if the allocator failed.
+    fn push_front(&mut self, v: Value) -> bool {
+        let lp = self.lp_mut();
+        let head = first(lp.0).unwrap_or(std::ptr::null_mut());
+        if lp.insert_val(v, Placement::Before, head).is_none() {
+            return false;
+        }
+        self.count += 1;
+        true
+    }
+
+    fn values(&self) -> Vec<Value> {
+        let mut values = Vec::with_capacity(self.count);
+        let lp = self.lp();
+        let mut ele = first(lp.0);
+        while let Some(p) = ele {
+            values.push(get(p));
+            ele = next(lp.0, p);
+        }
+        values
+    }
+
+    /// Move the entries from 'at' onwards to a new node. Returns None,
+    /// leaving the node untouched, if the allocator failed.
+    fn split_off(&mut self, at: usize) -> Option<Node> {
+        let mut right = Node::new();
+        if at < self.count {
+            if !right.lp_mut().try_extend(self.values().into_iter().skip(at)) {
+                return None;
+            }
+            right.count = self.count - at;
+            self.lp_mut().delete_range(at as isize, right.count as u32);
+            self.count = at;
+        }
+        Some(right)
+    }
+}
+
+/// Rebuild a listpack from its LZF compressed bytes.
+fn inflate(data: &[u8], size: usize) -> Listpack {
+    let bytes = lzf::decompress(data, size).expect("corrupt compressed quicklist node");
+    Listpack::from_vec(bytes).expect("corrupt compressed quicklist node")
+}
+
+/// A list of bounded listpack nodes, like the Redis quicklist. Every node is
+/// kept within the configured fill, so mutations only move the bytes of a
+/// single small listpack. Full nodes are split on insertion and neighbour
+/// nodes that fit together are merged back.
+pub struct Quicklist {
+    pub(crate) nodes: VecDeque<Node>,
+    fill: Fill,
+    count: usize,
+    compress_depth: usize,
+    /// Set when nodes were decompressed to be read.
+    accessed: Cell<bool>,
+}
+
+impl Quicklist {
+    pub fn new() -> Quicklist {
+        Quicklist::with_fill(Fill::default())
+    }
+
+    pub fn with_fill(fill: Fill) -> Quicklist {
+        Quicklist::with_options(fill, 0)
+    }
+
+    /// Create an empty quicklist that keeps the nodes more than
+    /// 'compress_depth' nodes away from either end LZF compressed, like
+    /// Redis `list-compress-depth`. A depth of 0 disables compression.
+    pub fn with_options(fill: Fill, compress_depth: usize) -> Quicklist {
+        Quicklist {
+            nodes: VecDeque::new(),
+            fill,
+            count: 0,
+            compress_depth,
+            accessed: Cell::new(false),
+        }
+    }
+
+    #[inline]
+    pub fn fill(&self) -> Fill {
+        self.fill
+    }
+
+    #[inline]
+    pub fn compress_depth(&self) -> usize {
+        self.compress_depth
+    }
+
+    /// Number of entries.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Number of listpack nodes.
+    #[inline]
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Add 'v' at the head. Returns false, leaving the list untouched, if
+    /// the allocator failed.
+    pub fn push_front<V: ToValue>(&mut self, v: V) -> bool {
+        let v = v.to_value();
+        let fits = match self.nodes.front() {
+            Some(node) => node.allows(self.fill, &v),
+            None => false
+        };
+        if !fits {
+            self.nodes.push_front(Node::new());
+        }
+        if !self.nodes[0].push_front(v) {
+            if !fits {
+                self.nodes.pop_front();
+            }
+            return false;
+        }
+        self.count += 1;
+        self.compress(&[]);
+        true
+    }
+
+    /// Add 'v' at the tail. Returns false, leaving the list untouched, if
+    /// the allocator failed.
+    pub fn push_back<V: ToValue>(&mut self, v: V) -> bool {
+        let v = v.to_value();
+        let fits = match self.nodes.back() {
+            Some(node) => node.allows(self.fill, &v),
+            None => false
+        };
+        if !fits {
+            self.nodes.push_back(Node::new());
+        }
+        let last = self.nodes.len() - 1;
+        if !self.nodes[last].push_back(v) {
+            if !fits {
+                self.nodes.pop_back();
+            }
+            return false;
+        }
+        self.count += 1;
+        self.compress(&[]);
+        true
+    }
+
+    pub fn pop_front(&mut self) -> Option<OwnedValue> {
+        self.remove(0)
+    }
+
+    pub fn pop_back(&mut self) -> Option<OwnedValue> {
+        self.remove(-1)
+    }
+
+    /// Find the node holding the entry at 'index', and the offset of the
+    /// entry within the node. Negative indexes count from the tail.
+    fn locate(&self, index: isize) -> Option<(usize, usize)> {
+        let index = if index < 0 { index + self.count as isize } else { index };
+        if index < 0 || index as usize >= self.count {
+            return None;
+        }
+
+        let mut index = index as usize;
+        if index < self.count / 2 {
+            for (n, node) in self.nodes.iter().enumerate() {
+                if index < node.count {
+                    return Some((n, index));
+                }
+                index -= node.count;
+            }
+        } else {
+            // Scan from the tail.
+            let mut rindex = self.count - 1 - index;
+            for (n, node) in self.nodes.iter().enumerate().rev() {
+                if rindex < node.count {
+                    return Some((n, node.count - 1 - rindex));
+                }
+                rindex -= node.count;
+            }
+        }
+        None
+    }
+
+    /// Entry at 'index'. Negative indexes count from the tail.
+    pub fn get(&self, index: isize) -> Option<Value> {
+        let (n, offset) = self.locate(index)?;
+        seek(self.node_lp(n).0, offset as isize).map(get)
+    }
+
+    /// Insert 'v' before the entry at 'index'. Returns false if the index is
+    /// out of range or the allocator failed.
+    pub fn insert_before<V: ToValue>(&mut self, index: isize, v: V) -> bool {
+        self.insert(index, Placement::Before, v.to_value())
+    }
+
+    /// Insert 'v' after the entry at 'index'. Returns false if the index is
+    /// out of range or the allocator failed.
+    pub fn insert_after<V: ToValue>(&mut self, index: isize, v: V) -> bool {
+        self.insert(index, Placement::After, v.to_value())
+    }
+
+    fn insert(&mut self, index: isize, place: Placement, v: Value) -> bool {
+        let (n, offset) = match self.locate(index) {
+            Some(location) => location,
+            None => return false
+        };
+        let fill = self.fill;
+
+        if self.nodes[n].allows(fill, &v) {
+            let node = &mut self.nodes[n];
+            let lp = node.lp_mut();
+            let target = match seek(lp.0, offset as isize) {
+                Some(target) => target,
+                None => return false
+            };
+            if lp.insert_val(v, place, target).is_none() {
+                return false;
+            }
+            node.count += 1;
+        } else {
+            let at = match place {
+                Placement::Before => offset,
+                Placement::After => offset + 1,
+            };
+
+            if at == 0 && n > 0 && self.nodes[n - 1].allows(fill, &v) {
+                // Append to the tail of the previous node.
+                if !self.nodes[n - 1].push_back(v) {
+                    return false;
+                }
+            } else if at == self.nodes[n].count
+                && n + 1 < self.nodes.len()
+                && self.nodes[n + 1].allows(fill, &v) {
+                // Prepend to the head of the next node.
+                if !self.nodes[n + 1].push_front(v) {
+                    return false;
+                }
+            } else {
+                // Split the full node and add the entry to the left half.
+                let right = match self.nodes[n].split_off(at) {
+                    Some(right) => right,
+                    None => return false
+                };
+                if right.count > 0 {
+                    self.nodes.insert(n + 1, right);
+                }
+                // On failure the node stays split, with all its entries.
+                if self.nodes[n].allows(fill, &v) {
+                    if !self.nodes[n].push_back(v) {
+                        return false;
+                    }
+                } else {
+                    let mut node = Node::new();
+                    if !node.push_back(v) {
+                        return false;
+                    }
+                    self.nodes.insert(n + 1, node);
+                }
+                self.merge_around(n + 1);
+            }
+        }
+
+        self.count += 1;
+        self.compress(&[n.saturating_sub(1), n, n + 1, n + 2]);
+        true
+    }
+
+    /// Remove the entry at 'index' and return it. Negative indexes count
+    /// from the tail.
+    pub fn remove(&mut self, index: isize) -> Option<OwnedValue> {
+        let (n, offset) = self.locate(index)?;
+        let value = {
+            let node = &mut self.nodes[n];
+            let lp = node.lp_mut();
+            let p = seek(lp.0, offset as isize)?;
+            let value = get(p).to_owned_value();
+            lp.delete(p)?;
+            node.count -= 1;
+            value
+        };
+        self.count -= 1;
+
+        if self.nodes[n].count == 0 {
+            self.nodes.remove(n);
+        } else {
+            self.merge_around(n);
+        }
+        self.compress(&[n.saturating_sub(1), n]);
+        Some(value)
+    }
+
+    /// Merge the node at 'n' with its neighbours when they fit together.
+    fn merge_around(&mut self, n: usize) {
+        if n + 1 < self.nodes.len() {
+            self.try_merge(n);
+        }
+        if n > 0 && n < self.nodes.len() {
+            self.try_merge(n - 1);
+        }
+    }
+
+    /// Merge the node at 'n + 1' into the node at 'n' if the result fits.
+    fn try_merge(&mut self, n: usize) -> bool {
+        let fits = {
+            let (left, right) = (&self.nodes[n], &self.nodes[n + 1]);
+            self.fill.fits(
+                left.count + right.count,
+                left.bytes() + right.bytes() - LP_OVERHEAD,
+            )
+        };
+        if !fits {
+            return false;
+        }
+
+        // The values point into the right node, which is removed only once
+        // they were copied.
+        let values = self.nodes[n + 1].values();
+        if !self.nodes[n].lp_mut().try_extend(values) {
+            return false;
+        }
+        if let Some(right) = self.nodes.remove(n + 1) {
+            self.nodes[n].count += right.count;
+        }
+        true
+    }
+
+    /// Iterate the entries from head to tail, or in reverse with `rev()`.
+    pub fn iter(&self) -> Iter<'_> {
+        let tail = self.nodes.len().saturating_sub(1);
+        Iter {
+            ql: self,
+            front: (0, if self.nodes.is_empty() { None } else { first(self.node_lp(0).0) }),
+            back: (tail, if self.nodes.is_empty() { None } else { last(self.node_lp(tail).0) }),
+            remaining: self.count,
+        }
+    }
+
+    /// Listpack of the node at 'n', decompressed if needed.
+    fn node_lp(&self, n: usize) -> &Listpack {
+        let node = &self.nodes[n];
+        if node.is_compressed() {
+            self.accessed.set(true);
+        }
+        node.lp()
+    }
+
+    /// Keep the nodes within `compress_depth` of either end uncompressed and
+    /// compress the interior nodes at the edges of that window, at the
+    /// indexes in 'touched', and those decompressed to be read, like
+    /// `__quicklistCompress()`.
+    fn compress(&mut self, touched: &[usize]) {
+        let depth = self.compress_depth;
+        if depth == 0 {
+            return;
+        }
+        let len = self.nodes.len();
+
+        for i in 0..std::cmp::min(depth, len) {
+            self.nodes[i].decompress();
+            self.nodes[len - 1 - i].decompress();
+        }
+        if len <= depth * 2 {
+            return;
+        }
+
+        if self.accessed.replace(false) {
+            for node in self.nodes.iter_mut() {
+                if node.recompress.get() {
+                    node.compress();
+                }
+            }
+        }
+        self.nodes[depth].compress();
+        self.nodes[len - 1 - depth].compress();
+        for &n in touched {
+            if n >= depth && n < len - depth {
+                self.nodes[n].compress();
+            }
+        }
+    }
+}
+
+impl Default for Quicklist {
+    fn default() -> Self {
+        Quicklist::new()
+    }
+}
+
+/// Iterator over the entries of a `Quicklist`.
+pub struct Iter<'a> {
+    ql: &'a Quicklist,
+    front: (usize, Option<element>),
+    back: (usize, Option<element>),
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        while self.remaining > 0 {
+            match self.front.1 {
+                Some(p) => {
+                    self.front.1 = next(self.ql.node_lp(self.front.0).0, p);
+                    self.remaining -= 1;
+                    return Some(get(p));
+                }
+                None => {
+                    self.front.0 += 1;
+                    if self.front.0 >= self.ql.nodes.len() {
+                        return None;
+                    }
+                    self.front.1 = first(self.ql.node_lp(self.front.0).0);
+                }
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Value> {
+        while self.remaining > 0 {
+            match self.back.1 {
+                Some(p) => {
+                    self.back.1 = prev(self.ql.node_lp(self.back.0).0, p);
+                    self.remaining -= 1;
+                    return Some(get(p));
+                }
+                None => {
+                    if self.back.0 == 0 {
+                        return None;
+                    }
+                    self.back.0 -= 1;
+                    self.back.1 = last(self.ql.node_lp(self.back.0).0);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(ql: &Quicklist) -> Vec<i64> {
+        ql.iter().map(|v| v.as_int().unwrap()).collect()
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut ql = Quicklist::with_fill(Fill::Entries(3));
+        for i in 0..5 {
+            assert!(ql.push_back(i));
+        }
+        for i in 1..4 {
+            assert!(ql.push_front(-i));
+        }
+        assert_eq!(ql.len(), 8);
+        assert_eq!(ql.node_count(), 3);
+        assert_eq!(ints(&ql), vec![-3, -2, -1, 0, 1, 2, 3, 4]);
+        assert_eq!(ql.iter().rev().map(|v| v.as_int().unwrap()).collect::<Vec<_>>(),
+                   vec![4, 3, 2, 1, 0, -1, -2, -3]);
+
+        assert_eq!(ql.pop_front(), Some(OwnedValue::Int(-3)));
+        assert_eq!(ql.pop_back(), Some(OwnedValue::Int(4)));
+        assert_eq!(ql.len(), 6);
+
+        assert_eq!(ql.get(0).unwrap().as_int(), Some(-2));
+        assert_eq!(ql.get(4).unwrap().as_int(), Some(2));
+        assert_eq!(ql.get(-1).unwrap().as_int(), Some(3));
+        assert!(ql.get(6).is_none());
+        assert!(ql.get(-7).is_none());
+
+        while ql.pop_front().is_some() {}
+        assert!(ql.is_empty());
+        assert_eq!(ql.node_count(), 0);
+    }
+
+    #[test]
+    fn insert_splits_and_removals_merge() {
+        let mut ql = Quicklist::with_fill(Fill::Entries(4));
+        for i in 0..8 {
+            ql.push_back(i * 10);
+        }
+        assert_eq!(ql.node_count(), 2);
+
+        // Both nodes are full, so this splits the first one.
+        assert!(ql.insert_after(1, 15));
+        assert!(ql.insert_before(0, -10));
+        assert!(ql.insert_after(-1, 80));
+        assert!(!ql.insert_before(100, 1));
+        assert_eq!(ints(&ql), vec![-10, 0, 10, 15, 20, 30, 40, 50, 60, 70, 80]);
+        for node in ql.nodes.iter() {
+            assert!(node.count <= 4);
+            assert_eq!(node.count, length(node.lp().0) as usize);
+        }
+
+        for _ in 0..6 {
+            ql.remove(1);
+        }
+        assert_eq!(ints(&ql), vec![-10, 50, 60, 70, 80]);
+        assert_eq!(ql.node_count(), 2);
+        assert_eq!(ql.iter().rev().count(), 5);
+    }
+
+    #[test]
+    fn byte_fill() {
+        assert_eq!(Fill::from_redis(-2), Fill::Bytes(8192));
+        assert_eq!(Fill::from_redis(128), Fill::Entries(128));
+
+        let mut ql = Quicklist::with_fill(Fill::Bytes(64));
+        let s = "0123456789";
+        for _ in 0..20 {
+            ql.push_back(s);
+        }
+        for node in ql.nodes.iter() {
+            assert!(node.bytes() <= 64);
+        }
+        assert_eq!(ql.len(), 20);
+        assert!(ql.iter().all(|v| v == s.into()));
+    }
+
+    #[test]
+    fn compress_depth() {
+        let mut ql = Quicklist::with_options(Fill::Entries(4), 1);
+        let entry = |i: i32| format!("cold-entry-cold-entry-{:04}", i);
+        for i in 0..40 {
+            ql.push_back(&*entry(i));
+        }
+        assert_eq!(ql.node_count(), 10);
+
+        let compressed = |ql: &Quicklist| -> Vec<bool> {
+            ql.nodes.iter().map(|node| node.is_compressed()).collect()
+        };
+        let mut expected = vec![true; 10];
+        expected[0] = false;
+        expected[9] = false;
+        assert_eq!(compressed(&ql), expected);
+
+        // Reading decompresses, the next mutation compresses again.
+        assert!(ql.get(17).unwrap() == (&*entry(17)).into());
+        assert!(!ql.nodes[4].is_compressed());
+        assert!(ql.iter().rev().enumerate().all(|(i, v)| v == (&*entry(39 - i as i32)).into()));
+        assert!(compressed(&ql).iter().all(|&c| !c));
+        ql.push_front("head");
+        assert_eq!(ql.node_count(), 11);
+        expected.insert(1, true);
+        assert_eq!(compressed(&ql), expected);
+
+        // Popping brings compressed nodes back into the window.
+        for _ in 0..37 {
+            ql.pop_front();
+        }
+        assert_eq!(ql.len(), 4);
+        assert!(compressed(&ql).iter().all(|&c| !c));
+        assert!(ql.get(0).unwrap() == (&*entry(36)).into());
+
+        // Small nodes are left alone.
+        let mut ql = Quicklist::with_options(Fill::Entries(2), 1);
+        for i in 0..10 {
+            ql.push_back(i);
+        }
+        assert!(ql.nodes.iter().all(|node| !node.is_compressed()));
+    }
+}
@@ -10,7 +10,9 @@
 /// formed or truncated. These advantages are, in the idea of the writer, worth
 /// the additional byte needed in the representation.
 ///
-///     <tot-bytes> <num-elements> <element-1> ... <element-N> <listpack-end-byte>
+/// ```text
+/// <tot-bytes> <num-elements> <element-1> ... <element-N> <listpack-end-byte>
+/// ```
 ///
 /// The six byte header, composed of the tot-bytes and num-elements fields is
 /// encoded in the following way:
@@ -38,11 +40,13 @@ use std;
 use std::alloc::*;
 use std::mem::size_of;
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub const EMPTY: &'static [u8] = &[];
 
 /// Listpacks are composed of elements that are either an derivative of a
 /// 64bit integer or a string blob.
+#[derive(Clone, Copy)]
 pub enum Value {
     Int(i64),
     String(*const u8, u32),
@@ -69,9 +73,42 @@ pub trait Allocator: Sized {
 
     fn alloc(&self, size: usize) -> listpack;
 
-    fn realloc(&self, lp: listpack, size: usize) -> listpack;
+    /// Resize the allocation 'lp' of 'old_size' bytes to 'new_size' bytes.
+    /// The header isn't updated until the reallocation succeeded, and isn't
+    /// written at all when there is none, so it can't be used to tell the
+    /// size of the allocation.
+    ///
+    /// Shrinking happens after the bytes were moved, so a null result then
+    /// isn't fatal: the listpack keeps 'lp' and passes 'new_size' as its size
+    /// from then on.
+    fn realloc(&self, lp: listpack, old_size: usize, new_size: usize) -> listpack;
 
-    fn dealloc(&self, lp: listpack);
+    /// Free the allocation 'lp' of 'size' bytes.
+    fn dealloc(&self, lp: listpack, size: usize);
+}
+
+/// An allocator can be shared by reference, for example to allocate many
+/// listpacks out of the same arena.
+impl<'a, A: Allocator> Allocator for &'a A {
+    #[inline(always)]
+    fn has_header(&self) -> bool {
+        (**self).has_header()
+    }
+
+    #[inline(always)]
+    fn alloc(&self, size: usize) -> listpack {
+        (**self).alloc(size)
+    }
+
+    #[inline(always)]
+    fn realloc(&self, lp: listpack, old_size: usize, new_size: usize) -> listpack {
+        (**self).realloc(lp, old_size, new_size)
+    }
+
+    #[inline(always)]
+    fn dealloc(&self, lp: listpack, size: usize) {
+        (**self).dealloc(lp, size)
+    }
 }
 
 const INTBUF_SIZE: usize = 21;
@@ -170,7 +207,7 @@ pub fn str_len_6bit(p: *mut u8) -> u32 {
 pub fn str_len_12bit(p: *mut u8) -> u32 {
     unsafe {
         u32::from_le(
-            ((*p) & 0xFu8) as u32 | (*p.offset(1)) as u32
+            (((*p) & 0xFu8) as u32) << 8 | (*p.offset(1)) as u32
         )
     }
 }
@@ -290,6 +327,61 @@ pub fn encode_int(mut v: i64, intenc: &mut [u8; MAX_INT_ENCODING_LEN]) -> u64 {
     }
 }
 
+/// Return the integer represented by the string 's' if, and only if, the
+/// string is the canonical decimal representation of a signed 64 bit
+/// integer. This mirrors `lpStringToInt64()`, so strings with leading zeros,
+/// spaces, a '+' sign or "-0" are not considered integers.
+pub fn string_to_int(s: &[u8]) -> Option<i64> {
+    let slen = s.len();
+    if slen == 0 || slen > 20 {
+        return None;
+    }
+
+    // Special case: first and only digit is 0.
+    if slen == 1 && s[0] == b'0' {
+        return Some(0);
+    }
+
+    let mut p = 0;
+    let negative = s[0] == b'-';
+    if negative {
+        p += 1;
+        // Abort on only a negative sign.
+        if p == slen {
+            return None;
+        }
+    }
+
+    // First digit should be 1-9, otherwise the string should just be 0.
+    let mut v: u64 = match s[p] {
+        b'1'..=b'9' => (s[p] - b'0') as u64,
+        _ => return None
+    };
+    p += 1;
+
+    while p < slen {
+        match s[p] {
+            b'0'..=b'9' => {
+                v = v.checked_mul(10)?.checked_add((s[p] - b'0') as u64)?;
+            }
+            _ => return None
+        }
+        p += 1;
+    }
+
+    if negative {
+        if v > (i64::MAX as u64) + 1 {
+            None
+        } else {
+            Some((v as i64).wrapping_neg())
+        }
+    } else if v > i64::MAX as u64 {
+        None
+    } else {
+        Some(v as i64)
+    }
+}
+
 #[inline(always)]
 pub fn string_front_len(size: u32) -> u64 {
     if size < 64 {
@@ -301,6 +393,26 @@ pub fn string_front_len(size: u32) -> u64 {
     }
 }
 
+/// Write the encoding and length of a string of 'size' bytes, returning the
+/// number of bytes written.
+unsafe fn encode_string_front(buf: *mut u8, size: u32) -> u32 {
+    if size < 64 {
+        *buf = size as u8 | ENCODING_6BIT_STR;
+        1
+    } else if size < 4096 {
+        *buf = (size >> 8) as u8 | ENCODING_12BIT_STR;
+        *buf.offset(1) = (size & 0xff) as u8;
+        2
+    } else {
+        *buf = ENCODING_32BIT_STR;
+        *buf.offset(1) = (size & 0xff) as u8;
+        *buf.offset(2) = ((size >> 8) & 0xff) as u8;
+        *buf.offset(3) = ((size >> 16) & 0xff) as u8;
+        *buf.offset(4) = ((size >> 24) & 0xff) as u8;
+        5
+    }
+}
+
 fn encode_string(buf: *mut u8, ele: *const u8, size: u32) -> u32 {
     unsafe {
         if size < 64 {
@@ -628,9 +740,8 @@ pub fn get_encoded_size(p: element) -> u32 {
 
 /// Return the number of elements inside the listpack. This function attempts
 /// to use the cached value when within range, otherwise a full scan is
-/// needed. As a side effect of calling this function, the listpack header
-/// could be modified, because if the count is found to be already within
-/// the 'numele' header field range, the new value is set.
+/// needed. The listpack is never written to, so it is safe to call on a
+/// listpack that is shared between threads.
 #[inline(always)]
 pub fn length(lp: listpack) -> u32 {
     let numele = get_num_elements(lp);
@@ -640,24 +751,13 @@ pub fn length(lp: listpack) -> u32 {
         // Too many elements inside the listpack. We need to scan in order
         // to get the total number.
         let mut count: u32 = 0;
-        match first(lp) {
-            Some(mut p) => {
+        if let Some(mut p) = first(lp) {
+            count = count + 1;
+            while let Some(ele) = next(lp, p) {
+                p = ele;
                 count = count + 1;
-                while let Some(ele) = next(lp, p) {
-                    p = ele;
-                    count = count + 1;
-                }
             }
-            None => {
-                set_num_elements(lp, 0)
-            }
-        }
-
-        // If the count is again within range of the header numele field, set it.
-        if count < HDR_NUMELE_UNKNOWN as u32 {
-            set_num_elements(lp, count as u16);
         }
-
         count
     }
 }
@@ -724,6 +824,37 @@ impl Value {
         }
     }
 
+    /// Return the integer this value represents, either because it is
+    /// integer encoded or because it is a string holding the canonical
+    /// decimal representation of an integer.
+    #[inline]
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Value::Int(v) => Some(v),
+            Value::String(_, _) => string_to_int(self.as_bytes())
+        }
+    }
+
+    /// Return the most compact value representing the string 's', which is
+    /// an integer if 's' is the canonical representation of one. This is how
+    /// Redis chooses the encoding of the strings it inserts in a listpack.
+    #[inline]
+    pub fn compact(s: &[u8]) -> Value {
+        match string_to_int(s) {
+            Some(v) => Value::Int(v),
+            None => Value::String(s.as_ptr(), s.len() as u32)
+        }
+    }
+
+    /// Copy the value out of the listpack.
+    #[inline]
+    pub fn to_owned_value(&self) -> OwnedValue {
+        match *self {
+            Value::Int(v) => OwnedValue::Int(v),
+            Value::String(_, _) => OwnedValue::String(self.as_bytes().to_vec())
+        }
+    }
+
     #[inline(always)]
     pub fn encoded_size(&self) -> u32 {
         match *self {
@@ -888,7 +1019,7 @@ impl Value {
                         size as usize,
                     );
                     // Encode backlen
-                    Value::encode_backlen(dst.offset(encoded_size as isize), size);
+                    Value::encode_backlen(dst.offset(1 + size as isize), 1 + size);
                 } else if size < 4096 {
                     *dst.offset(0) = (size >> 8) as u8 | ENCODING_12BIT_STR;
                     *dst.offset(1) = (size & 0xff) as u8;
@@ -898,7 +1029,7 @@ impl Value {
                         size as usize,
                     );
                     // Encode backlen
-                    Value::encode_backlen(dst.offset(encoded_size as isize), size);
+                    Value::encode_backlen(dst.offset(2 + size as isize), 2 + size);
                 } else {
                     *dst.offset(0) = ENCODING_32BIT_STR;
                     *dst.offset(1) = (size & 0xff) as u8;
@@ -911,13 +1042,341 @@ impl Value {
                         size as usize,
                     );
                     // Encode backlen
-                    Value::encode_backlen(dst.offset(encoded_size as isize), size);
+                    Value::encode_backlen(dst.offset(5 + size as isize), 5 + size);
                 }
             }
         }
     }
 }
 
+/// Values are compared the way `lpCompare()` does it in Redis: an integer
+/// and the string holding its canonical decimal representation are the
+/// same value.
+impl PartialEq for Value {
+    #[inline]
+    fn eq(&self, other: &Value) -> bool {
+        match (self.as_int(), other.as_int()) {
+            (Some(v), Some(v2)) => v == v2,
+            (None, None) => self.as_bytes() == other.as_bytes(),
+            _ => false
+        }
+    }
+}
+
+impl Eq for Value {}
+
+/// Integers (including canonical integer strings) are ordered numerically
+/// and sort before every other string. Strings are ordered as `memcmp()`
+/// orders them.
+impl PartialOrd for Value {
+    #[inline]
+    fn partial_cmp(&self, other: &Value) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> std::cmp::Ordering {
+        match (self.as_int(), other.as_int()) {
+            (Some(v), Some(v2)) => v.cmp(&v2),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => self.as_bytes().cmp(other.as_bytes())
+        }
+    }
+}
+
+impl std::hash::Hash for Value {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        match self.as_int() {
+            Some(v) => v.hash(state),
+            None => self.as_bytes().hash(state)
+        }
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Value::Int(v) => f.debug_tuple("Int").field(&v).finish(),
+            Value::String(_, _) => f.debug_tuple("String")
+                .field(&String::from_utf8_lossy(self.as_bytes()))
+                .finish()
+        }
+    }
+}
+
+/// Owned counterpart of `Value` which doesn't borrow the memory of a
+/// listpack, so it stays valid after the listpack is mutated or dropped.
+/// It compares, orders and hashes like `Value`.
+#[derive(Clone)]
+pub enum OwnedValue {
+    Int(i64),
+    String(Vec<u8>),
+}
+
+impl OwnedValue {
+    /// Borrow the owned value as a `Value`.
+    #[inline]
+    pub fn as_value(&self) -> Value {
+        match *self {
+            OwnedValue::Int(v) => Value::Int(v),
+            OwnedValue::String(ref s) => Value::String(s.as_ptr(), s.len() as u32)
+        }
+    }
+}
+
+impl PartialEq for OwnedValue {
+    #[inline]
+    fn eq(&self, other: &OwnedValue) -> bool {
+        self.as_value() == other.as_value()
+    }
+}
+
+impl Eq for OwnedValue {}
+
+impl PartialOrd for OwnedValue {
+    #[inline]
+    fn partial_cmp(&self, other: &OwnedValue) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OwnedValue {
+    #[inline]
+    fn cmp(&self, other: &OwnedValue) -> std::cmp::Ordering {
+        self.as_value().cmp(&other.as_value())
+    }
+}
+
+impl std::fmt::Debug for OwnedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.as_value().fmt(f)
+    }
+}
+
+impl std::hash::Hash for OwnedValue {
+    #[inline]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.as_value().hash(state)
+    }
+}
+
+/// Return true if the element pointed by 'p' is equal to the string 's'.
+/// Like `lpCompare()`, an integer encoded element is compared by parsing 's'
+/// as an integer instead of rendering the element as a string.
+pub fn compare(p: element, s: &[u8]) -> bool {
+    match get(p) {
+        Value::Int(v) => string_to_int(s) == Some(v),
+        value => value.as_bytes() == s
+    }
+}
+
+/// Validate the integrity of the serialized listpack 'bytes' before using it,
+/// like a deep `lpValidateIntegrity()`: the header must match the size, all
+/// the entries must have a valid encoding, fit in the buffer and end with a
+/// matching backlen, and the element count must match the header unless
+/// it's unknown.
+pub fn validate(bytes: &[u8]) -> bool {
+    let size = bytes.len();
+    if size < HDR_USIZE + 1 || bytes[size - 1] != EOF {
+        return false;
+    }
+    let lp = bytes.as_ptr() as listpack;
+    if get_total_bytes(lp) as usize != size {
+        return false;
+    }
+
+    let mut offset = HDR_USIZE;
+    let mut count = 0u32;
+    while bytes[offset] != EOF {
+        let b = bytes[offset];
+        // Make sure the string length can be read before decoding it.
+        let len_bytes = if is_12bit_str(b) {
+            2
+        } else if is_32bit_str(b) {
+            5
+        } else {
+            1
+        };
+        if offset + len_bytes >= size {
+            return false;
+        }
+        let encoded = if is_32bit_str(b) {
+            5 + str_len_32bit(unsafe { lp.add(offset) }) as usize
+        } else {
+            get_encoded_size(unsafe { lp.add(offset) }) as usize
+        };
+        if encoded == 0 {
+            return false;
+        }
+
+        let end = offset + encoded + backlen_size(encoded as u64) as usize;
+        if end >= size || decode_backlen(unsafe { lp.add(end - 1) }) != encoded as u64 {
+            return false;
+        }
+        offset = end;
+        count += 1;
+    }
+
+    offset == size - 1
+        && (get_num_elements(lp) == HDR_NUMELE_UNKNOWN || get_num_elements(lp) as u32 == count)
+}
+
+/// Encoding of a listpack entry, one per `ENCODING_*` type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Uint7Bit,
+    Str6Bit,
+    Int13Bit,
+    Str12Bit,
+    Int16Bit,
+    Int24Bit,
+    Int32Bit,
+    Int64Bit,
+    Str32Bit,
+}
+
+impl Encoding {
+    /// All the encodings, in the order they are indexed in `Stats`.
+    pub const ALL: [Encoding; 9] = [
+        Encoding::Uint7Bit,
+        Encoding::Str6Bit,
+        Encoding::Int13Bit,
+        Encoding::Str12Bit,
+        Encoding::Int16Bit,
+        Encoding::Int24Bit,
+        Encoding::Int32Bit,
+        Encoding::Int64Bit,
+        Encoding::Str32Bit,
+    ];
+
+    /// Return the encoding of an entry starting with the byte 'b', or None
+    /// if it isn't a valid one, like the EOF byte.
+    pub fn of(b: u8) -> Option<Encoding> {
+        if is_7bit_uint(b) {
+            Some(Encoding::Uint7Bit)
+        } else if is_6bit_str(b) {
+            Some(Encoding::Str6Bit)
+        } else if is_13bit_int(b) {
+            Some(Encoding::Int13Bit)
+        } else if is_12bit_str(b) {
+            Some(Encoding::Str12Bit)
+        } else if is_16bit_int(b) {
+            Some(Encoding::Int16Bit)
+        } else if is_24bit_int(b) {
+            Some(Encoding::Int24Bit)
+        } else if is_32bit_int(b) {
+            Some(Encoding::Int32Bit)
+        } else if is_64bit_int(b) {
+            Some(Encoding::Int64Bit)
+        } else if is_32bit_str(b) {
+            Some(Encoding::Str32Bit)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Uint7Bit => "7bit uint",
+            Encoding::Str6Bit => "6bit str",
+            Encoding::Int13Bit => "13bit int",
+            Encoding::Str12Bit => "12bit str",
+            Encoding::Int16Bit => "16bit int",
+            Encoding::Int24Bit => "24bit int",
+            Encoding::Int32Bit => "32bit int",
+            Encoding::Int64Bit => "64bit int",
+            Encoding::Str32Bit => "32bit str",
+        }
+    }
+
+    #[inline]
+    pub fn is_string(&self) -> bool {
+        matches!(*self, Encoding::Str6Bit | Encoding::Str12Bit | Encoding::Str32Bit)
+    }
+}
+
+/// Number of entries with an encoding and the bytes they take, backlens
+/// excluded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EncodingStats {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Memory usage of a listpack, see `stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub total_bytes: usize,
+    pub entries: usize,
+    /// Per encoding, indexed like `Encoding::ALL`.
+    pub encodings: [EncodingStats; 9],
+    /// The header and the EOF byte.
+    pub header_bytes: usize,
+    pub backlen_bytes: usize,
+    /// String entries that are the canonical representation of an integer,
+    /// and the bytes they would save integer encoded.
+    pub int_strings: usize,
+    pub int_string_savings: usize,
+}
+
+impl Stats {
+    #[inline]
+    pub fn encoding(&self, encoding: Encoding) -> EncodingStats {
+        self.encodings[encoding as usize]
+    }
+
+    /// Bytes taken by the entries, encodings and backlens.
+    #[inline]
+    pub fn entry_bytes(&self) -> usize {
+        self.total_bytes - self.header_bytes
+    }
+
+    /// Average size of an entry, backlen included.
+    pub fn average_entry_size(&self) -> f64 {
+        if self.entries == 0 {
+            0.0
+        } else {
+            self.entry_bytes() as f64 / self.entries as f64
+        }
+    }
+}
+
+/// Return the memory usage of the listpack 'lp': the bytes taken by every
+/// encoding, the header and the backlens, and how much could be saved by
+/// integer encoding the strings that hold an integer, like Redis would have.
+pub fn stats(lp: listpack) -> Stats {
+    let mut stats = Stats {
+        total_bytes: get_total_bytes(lp) as usize,
+        header_bytes: HDR_USIZE + 1,
+        ..Stats::default()
+    };
+
+    let mut p = first(lp);
+    while let Some(ele) = p {
+        let encoding = match Encoding::of(unsafe { *ele }) {
+            Some(encoding) => encoding,
+            None => break
+        };
+        let size = get_encoded_size(ele);
+        let backlen = backlen_size(size as u64);
+        stats.entries += 1;
+        stats.backlen_bytes += backlen as usize;
+        stats.encodings[encoding as usize].entries += 1;
+        stats.encodings[encoding as usize].bytes += size as usize;
+
+        if encoding.is_string() {
+            if let Some(v) = string_to_int(get(ele).as_bytes()) {
+                stats.int_strings += 1;
+                stats.int_string_savings += (size + backlen - Value::Int(v).size_for_write()) as usize;
+            }
+        }
+        p = next(lp, ele);
+    }
+    stats
+}
+
 #[inline(always)]
 pub fn first(lp: listpack) -> Option<element> {
     unsafe {
@@ -1086,7 +1545,58 @@ pub fn seek(lp: listpack, mut index: isize) -> Option<element> {
 }
 
 
+/// Find the element equal to the string 's', starting the scan at the element
+/// 'p' and moving towards the tail. After every comparison 'skip' elements
+/// are skipped without being compared, so that, for instance, a skip of 1
+/// only compares the fields of a listpack of field/value pairs. The integer
+/// interpretation of 's' is computed at most once, so integer encoded
+/// elements are compared without converting them to strings. If no element
+/// matches, None is returned.
+pub fn find(lp: listpack, mut p: element, s: &[u8], skip: u32) -> Option<element> {
+    if lp.is_null() || p.is_null() {
+        return None;
+    }
+
+    let mut skipcnt = 0;
+    // Integer interpretation of 's', resolved the first time an integer
+    // encoded element is compared.
+    let mut vll: Option<Option<i64>> = None;
+
+    unsafe {
+        while *p != EOF {
+            if skipcnt == 0 {
+                let b = *p;
+                if is_6bit_str(b) || is_12bit_str(b) || is_32bit_str(b) {
+                    if get(p).as_bytes() == s {
+                        return Some(p);
+                    }
+                } else {
+                    // Strings of 32 bytes or more can't be a valid integer.
+                    let needle = *vll.get_or_insert_with(|| {
+                        if s.len() >= 32 { None } else { string_to_int(s) }
+                    });
+                    if let Some(v) = needle {
+                        if get_int(p) == v {
+                            return Some(p);
+                        }
+                    }
+                }
+                // Reset skip count.
+                skipcnt = skip;
+            } else {
+                // Skip entry.
+                skipcnt -= 1;
+            }
+
+            p = next(lp, p)?;
+        }
+    }
+    None
+}
+
+
 /// Default listpack allocator that uses the system allocator.
+#[derive(Clone, Copy, Debug, Default)]
 pub struct DefaultAllocator;
 
 ///
@@ -1107,94 +1617,173 @@ impl Allocator for DefaultAllocator {
             alloc::alloc(
                 Layout::from_size_align_unchecked(
                     size,
-                    mem::size_of::<usize>(),
+                    mem::align_of::<u8>(),
                 )
             )
         }
     }
 
     #[inline(always)]
-    fn realloc(&self, lp: *mut u8, newsize: usize) -> *mut u8 {
+    fn realloc(&self, lp: *mut u8, old_size: usize, new_size: usize) -> *mut u8 {
         use std::mem;
         use std::alloc;
         unsafe {
             alloc::realloc(
                 lp,
                 Layout::from_size_align_unchecked(
-                    get_total_bytes(lp) as usize,
-                    mem::size_of::<usize>(),
+                    old_size,
+                    mem::align_of::<u8>(),
                 ),
-                newsize,
+                new_size,
             )
         }
     }
 
     #[inline(always)]
-    fn dealloc(&self, lp: *mut u8) {
+    fn dealloc(&self, lp: *mut u8, size: usize) {
         use std::mem;
         use std::alloc;
         unsafe {
             alloc::dealloc(
                 lp,
                 Layout::from_size_align_unchecked(
-                    get_total_bytes(lp) as usize,
-                    mem::size_of::<usize>(),
+                    size,
+                    mem::align_of::<u8>(),
                 ),
             )
         }
     }
 }
 
-///
-impl<'a> Allocator for &'a DefaultAllocator {
-    #[inline(always)]
-    fn has_header(&self) -> bool {
-        true
+/// System allocator for a single listpack that keeps track of the real size
+/// of the allocation, which may be larger than the serialized listpack. The
+/// spare bytes past the EOF byte are never part of the serialized form, but
+/// any mutation that fits in them doesn't need to call into the system
+/// allocator. Shrinking mutations keep the capacity around until
+/// `shrink_to_fit()` is called.
+pub struct CapacityAllocator {
+    // Only changed through the listpack being mutated, the atomic lets
+    // shared listpacks read it from other threads.
+    capacity: AtomicUsize,
+}
+
+impl CapacityAllocator {
+    pub fn new() -> CapacityAllocator {
+        CapacityAllocator {
+            capacity: AtomicUsize::new(0),
+        }
     }
 
-    #[inline(always)]
-    fn alloc(&self, size: usize) -> *mut u8 {
-        use std::mem;
-        use std::alloc;
-        unsafe {
-            alloc::alloc(
-                Layout::from_size_align_unchecked(
-                    size,
-                    mem::size_of::<usize>(),
-                )
-            )
+    /// Take over an allocation of 'capacity' bytes made by the global
+    /// allocator with an alignment of 1, such as the buffer of a `Vec<u8>`.
+    pub fn with_capacity(capacity: usize) -> CapacityAllocator {
+        CapacityAllocator {
+            capacity: AtomicUsize::new(capacity),
         }
     }
 
+    /// Size in bytes of the allocation holding the listpack.
     #[inline(always)]
-    fn realloc(&self, lp: *mut u8, newsize: usize) -> *mut u8 {
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Grow the allocation, if needed, so that at least 'additional' more
+    /// bytes can be written to the listpack without reallocating.
+    pub fn reserve(&self, lp: listpack, additional: usize) -> listpack {
+        let needed = get_total_bytes(lp) as usize + additional;
+        if needed <= self.capacity() {
+            lp
+        } else {
+            self.resize(lp, needed)
+        }
+    }
+
+    /// Release the spare capacity so the allocation is exactly as large as
+    /// the serialized listpack.
+    pub fn shrink_to_fit(&self, lp: listpack) -> listpack {
+        let size = get_total_bytes(lp) as usize;
+        if size == self.capacity() {
+            lp
+        } else {
+            self.resize(lp, size)
+        }
+    }
+
+    #[inline]
+    fn resize(&self, lp: listpack, size: usize) -> listpack {
         use std::mem;
         use std::alloc;
-        unsafe {
+        let lp = unsafe {
             alloc::realloc(
                 lp,
                 Layout::from_size_align_unchecked(
-                    get_total_bytes(lp) as usize,
-                    mem::size_of::<usize>(),
+                    self.capacity(),
+                    mem::align_of::<u8>(),
                 ),
-                newsize,
+                size,
             )
+        };
+        if !lp.is_null() {
+            self.capacity.store(size, Ordering::Relaxed);
         }
+        lp
+    }
+}
+
+impl Default for CapacityAllocator {
+    fn default() -> Self {
+        CapacityAllocator::new()
+    }
+}
+
+impl Clone for CapacityAllocator {
+    /// The capacity belongs to the allocation being tracked, so a clone
+    /// starts out without one.
+    fn clone(&self) -> Self {
+        CapacityAllocator::new()
     }
+}
 
+impl Allocator for CapacityAllocator {
     #[inline(always)]
-    fn dealloc(&self, lp: *mut u8) {
+    fn has_header(&self) -> bool {
+        true
+    }
+
+    #[inline]
+    fn alloc(&self, size: usize) -> *mut u8 {
+        let lp = DefaultAllocator.alloc(size);
+        if !lp.is_null() {
+            self.capacity.store(size, Ordering::Relaxed);
+        }
+        lp
+    }
+
+    #[inline]
+    fn realloc(&self, lp: *mut u8, _old_size: usize, new_size: usize) -> *mut u8 {
+        if new_size <= self.capacity() {
+            lp
+        } else {
+            self.resize(lp, new_size)
+        }
+    }
+
+    /// The whole capacity is freed, whatever the size of the listpack.
+    #[inline]
+    fn dealloc(&self, lp: *mut u8, _size: usize) {
         use std::mem;
         use std::alloc;
         unsafe {
             alloc::dealloc(
                 lp,
                 Layout::from_size_align_unchecked(
-                    get_total_bytes(lp) as usize,
-                    mem::size_of::<usize>(),
+                    self.capacity(),
+                    mem::align_of::<u8>(),
                 ),
             )
         }
+        self.capacity.store(0, Ordering::Relaxed);
     }
 }
 
@@ -1203,9 +1792,48 @@ impl<'a> Allocator for &'a DefaultAllocator {
 pub fn new<'a, A>(allocator: &'a A) -> listpack where A: Allocator {
     let lp = allocator.alloc(HDR_USIZE + 1);
     set_total_bytes(lp, HDR_USIZE as u32 + 1);
+    if allocator.has_header() {
+        set_num_elements(lp, 0);
+    } else {
+        // The count is never updated, so readers have to scan.
+        set_num_elements(lp, HDR_NUMELE_UNKNOWN);
+    }
+    unsafe {
+        *lp.offset(HDR_SIZE) = EOF;
+    }
     lp
 }
 
+/// Shrink 'lp' once its bytes were moved. If that fails 'lp' is kept, with
+/// unused bytes past the EOF byte.
+#[inline]
+fn shrink<'a, A>(allocator: &'a A, lp: listpack, old_size: usize, new_size: usize) -> listpack where A: Allocator {
+    let shrunk = allocator.realloc(lp, old_size, new_size);
+    if shrunk.is_null() {
+        lp
+    } else {
+        shrunk
+    }
+}
+
+/// Size in bytes of 'lp', EOF byte included. It is read from the header, or
+/// found by walking the elements up to the EOF byte when the allocator
+/// doesn't keep a header. Callers that track the size themselves can use
+/// the "_sized" variants of the write functions instead.
+#[inline]
+pub fn total_bytes<'a, A>(allocator: &'a A, lp: listpack) -> usize where A: Allocator {
+    if allocator.has_header() {
+        return get_total_bytes(lp) as usize;
+    }
+    unsafe {
+        let mut p = lp.offset(HDR_SIZE);
+        while *p != EOF {
+            p = skip(p);
+        }
+        (p as usize) - (lp as usize) + 1
+    }
+}
+
 #[inline]
 pub fn zigzag(n: i64) -> i64 {
     ((n >> 1) as i64) ^ (-((n & 1) as i64))
@@ -1215,21 +1843,36 @@ pub fn zigzag(n: i64) -> i64 {
 #[inline]
 pub fn insert<'a, A>(
     allocator: &'a A,
-    mut lp: listpack,
+    lp: listpack,
     v: Value,
     place: Placement,
     target: element
 ) -> Option<(listpack, element)>
     where A: Allocator {
-    unsafe {
-        let encoded_size = v.size_for_write();
-
-        // Calculate the old and new sizes.
-        let old_listpack_bytes = get_total_bytes(lp);
-        let new_listpack_bytes = old_listpack_bytes + encoded_size;
+    let size = total_bytes(allocator, lp);
+    insert_sized(allocator, lp, size, v, place, target).map(|(lp, p, _)| (lp, p))
+}
+
+/// Like `insert()` for a listpack of 'size' bytes. The new size is returned
+/// along with the listpack and the inserted element.
+pub fn insert_sized<'a, A>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize,
+    v: Value,
+    place: Placement,
+    target: element
+) -> Option<(listpack, element, usize)>
+    where A: Allocator {
+    unsafe {
+        let encoded_size = v.size_for_write() as usize;
+
+        // Calculate the old and new sizes.
+        let old_listpack_bytes = size;
+        let new_listpack_bytes = old_listpack_bytes + encoded_size;
 
         // Is it over the max size?
-        if new_listpack_bytes > u32::max_value() {
+        if new_listpack_bytes > u32::max_value() as usize {
             return None;
         }
 
@@ -1239,13 +1882,7 @@ pub fn insert<'a, A>(
                 Placement::Before => {
                     // Gracefully handle null pointer and EOF as an append.
                     if target.is_null() || *target == EOF {
-                        match append(allocator, lp, v) {
-                            Some(new_lp) => return Some((
-                                new_lp,
-                                last(new_lp).unwrap_or(std::ptr::null_mut())
-                            )),
-                            None => return None
-                        }
+                        return append_at_tail(allocator, lp, size, v);
                     } else {
                         // Target is already what we want.
                         target
@@ -1254,24 +1891,12 @@ pub fn insert<'a, A>(
                 Placement::After => {
                     // Gracefully handle null pointer and EOF as an append.
                     if target.is_null() || *target == EOF {
-                        match append(allocator, lp, v) {
-                            Some(new_lp) => return Some((
-                                new_lp,
-                                last(new_lp).unwrap_or(std::ptr::null_mut())
-                            )),
-                            None => return None
-                        }
+                        return append_at_tail(allocator, lp, size, v);
                     } else {
                         // Find next element so we can place it before.
                         match next(lp, target) {
                             Some(ele) => ele,
-                            None => match append(allocator, lp, v) {
-                                Some(new_lp) => return Some((
-                                    new_lp,
-                                    last(new_lp).unwrap_or(std::ptr::null_mut())
-                                )),
-                                None => return None
-                            }
+                            None => return append_at_tail(allocator, lp, size, v)
                         }
                     }
                 }
@@ -1286,7 +1911,7 @@ pub fn insert<'a, A>(
         let poff = (p as usize) - (lp as usize);
 
         // realloc to make room
-        lp = allocator.realloc(lp, new_listpack_bytes as usize);
+        lp = allocator.realloc(lp, old_listpack_bytes, new_listpack_bytes);
         if lp.is_null() {
             return None;
         }
@@ -1297,29 +1922,45 @@ pub fn insert<'a, A>(
         // we need to store the new one.
         std::ptr::copy(
             p,
-            p.offset(encoded_size as isize),
-            old_listpack_bytes as usize - poff,
+            p.add(encoded_size),
+            old_listpack_bytes - poff,
         );
 
         // Write value.
         // This overwrites the EOF byte at the end which will get added
         // immediately after this new value.
-        v.encode(p, encoded_size);
+        v.encode(p, encoded_size as u32);
 
         // Write EOF
         *lp.offset(new_listpack_bytes as isize - 1) = EOF;
 
-        // Update header
-        let num_elements = get_num_elements(lp);
-        if num_elements != HDR_NUMELE_UNKNOWN {
-            set_num_elements(lp, num_elements + 1);
+        if allocator.has_header() {
+            // Update header
+            let num_elements = get_num_elements(lp);
+            if num_elements != HDR_NUMELE_UNKNOWN {
+                set_num_elements(lp, num_elements + 1);
+            }
+            set_total_bytes(lp, new_listpack_bytes as u32);
         }
-        set_total_bytes(lp, new_listpack_bytes);
 
-        Some((lp, p))
+        Some((lp, p, new_listpack_bytes))
     }
 }
 
+/// Append 'v' for `insert_sized()`, returning the new element which sits
+/// where the EOF byte was.
+#[inline]
+fn append_at_tail<'a, A>(
+    allocator: &'a A,
+    lp: listpack,
+    size: usize,
+    v: Value
+) -> Option<(listpack, element, usize)>
+    where A: Allocator {
+    let (lp, new_size) = append_sized(allocator, lp, size, v)?;
+    Some((lp, unsafe { lp.add(size - 1) }, new_size))
+}
+
 ///
 #[inline]
 pub fn insert_int<'a, A, I>(
@@ -1365,155 +2006,203 @@ pub fn insert_string<'a, A, S>(
     insert(allocator, lp, v.as_value(), place, target)
 }
 
-///
-#[inline]
-pub fn replace<'a, A>(
+/// Insert all the values yielded by 'values' next to the 'target' element,
+/// keeping their order. Like `append_many()`, the allocation grows only
+/// once and the tail of the listpack is moved with a single memmove. A null
+/// or EOF target inserts at the tail. On success the new listpack is
+/// returned along with a pointer to the first inserted element.
+pub fn insert_many<'a, A, I>(
     allocator: &'a A,
-    mut lp: listpack,
-    mut p: element,
-    v: Value
+    lp: listpack,
+    values: I,
+    place: Placement,
+    target: element
 ) -> Option<(listpack, element)>
-    where A: Allocator {
-    unsafe {
-        // Let's try to be somewhat safe.
-        if lp.is_null() || p.is_null() || *p == EOF {
-            return None;
-        }
-
-        let encoded_size = v.size_for_write();
-        let old_value = get(p);
-        let old_size = old_value.size_for_write();
-
-        if old_size == encoded_size {
-            return Some((lp, p));
-        }
-
-        // Calculate size delta.
-        let delta = (encoded_size as isize) - (old_size as isize);
-        if delta == 0 {
-            // Same size!
-            // Encode in place.
-            v.encode(p, encoded_size);
-            return Some((lp, p));
-        }
+    where
+        A: Allocator,
+        I: IntoIterator<Item = Value> {
+    let size = total_bytes(allocator, lp);
+    insert_many_sized(allocator, lp, size, values, place, target).map(|(lp, p, _)| (lp, p))
+}
 
-        // Store the offset of the element 'p', so that we can obtain it's
-        // address again after a reallocation.
-        let p_uintptr = p as usize;
-        let lp_uintptr = lp as usize;
+/// Like `insert_many()` for a listpack of 'size' bytes. The new size is
+/// returned along with the listpack and the first inserted element.
+pub fn insert_many_sized<'a, A, I>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize,
+    values: I,
+    place: Placement,
+    target: element
+) -> Option<(listpack, element, usize)>
+    where
+        A: Allocator,
+        I: IntoIterator<Item = Value> {
+    unsafe {
+        let values: Vec<(Value, u32)> = values
+            .into_iter()
+            .map(|v| {
+                let encoded_size = v.size_for_write();
+                (v, encoded_size)
+            })
+            .collect();
 
-        // Bounds check
-        if p_uintptr <= lp_uintptr {
-            // Whoops!!! "p" is not within this listpack!
+        // Calculate the old and new sizes.
+        let old_listpack_bytes = size;
+        let batch_bytes: u64 = values
+            .iter()
+            .map(|&(_, encoded_size)| encoded_size as u64)
+            .sum();
+        let new_listpack_bytes = old_listpack_bytes as u64 + batch_bytes;
+        if new_listpack_bytes > u32::MAX as u64 {
             return None;
         }
-        // Calculate "p" offset.
-        let poff = p_uintptr - lp_uintptr;
 
-        // Calculate the old and new sizes.
-        let old_listpack_bytes = get_total_bytes(lp);
-        let new_listpack_bytes = (old_listpack_bytes as isize + delta) as usize;
-
-        // Bounds check
-        if poff > new_listpack_bytes {
-            // Whoops!!! "p" is not within this listpack!
+        // Find the offset of the element the batch is placed before.
+        let eof_off = old_listpack_bytes - 1;
+        let poff = if target.is_null() || *target == EOF {
+            eof_off
+        } else if !is_valid_element(lp, target, old_listpack_bytes) {
             return None;
+        } else {
+            match place {
+                Placement::Before => (target as usize) - (lp as usize),
+                Placement::After => (skip(target) as usize) - (lp as usize)
+            }
+        };
+
+        if values.is_empty() {
+            return Some((lp, lp.add(poff), old_listpack_bytes));
         }
 
-        // Is it over the max size?
-        if new_listpack_bytes > u32::max_value() as usize {
+        // realloc once to make room for the whole batch
+        lp = allocator.realloc(lp, old_listpack_bytes, new_listpack_bytes as usize);
+        if lp.is_null() {
             return None;
         }
 
-        if delta > 0 {
-            // Shift next element by delta bytes.
-            match next(lp, p) {
-                Some(ele) => {
-                    // Calculate the next element offset.
-                    let ele_uintptr = ele as usize;
-                    if ele_uintptr <= p_uintptr {
-                        return None;
-                    }
-                    let eleoff = ele_uintptr - lp_uintptr;
-
-                    // Grow allocation. We must do this before the shift since
-                    // it could potentially overflow the actual allocation.
-                    lp = allocator.realloc(lp, new_listpack_bytes);
-                    if lp.is_null() {
-                        return None;
-                    }
+        // Move the tail, EOF included, past the room for the batch.
+        let mut p = lp.add(poff);
+        std::ptr::copy(
+            p,
+            p.offset(batch_bytes as isize),
+            old_listpack_bytes - poff,
+        );
 
-                    // Update "p" to it's pointer in the potentially new allocation.
-                    p = lp.offset(poff as isize);
+        for &(ref v, encoded_size) in &values {
+            v.encode(p, encoded_size);
+            p = p.offset(encoded_size as isize);
+        }
 
-                    std::ptr::copy(
-                        lp.offset(eleoff as isize),
-                        lp.offset((eleoff as isize) + delta),
-                        old_listpack_bytes as usize - eleoff,
-                    );
+        if allocator.has_header() {
+            // Update header
+            let num_elements = get_num_elements(lp);
+            if num_elements != HDR_NUMELE_UNKNOWN {
+                let count = num_elements as usize + values.len();
+                if count < HDR_NUMELE_UNKNOWN as usize {
+                    set_num_elements(lp, count as u16);
+                } else {
+                    set_num_elements(lp, HDR_NUMELE_UNKNOWN);
                 }
-                None => {
-                    // Grow allocation. We must do this before the shift since
-                    // it could potentially overflow the actual allocation.
-                    lp = allocator.realloc(lp, new_listpack_bytes);
-                    if lp.is_null() {
-                        return None;
-                    }
+            }
+            set_total_bytes(lp, new_listpack_bytes as u32);
+        }
 
-                    // Update "p" to it's pointer in the potentially new allocation.
-                    p = lp.offset(poff as isize);
+        Some((lp, lp.add(poff), new_listpack_bytes as usize))
+    }
+}
 
-                    // Treat it as EOF
-                    v.encode(p, encoded_size);
+/// Replace the element pointed by 'p' with the value 'v', moving the rest of
+/// the listpack when the encoded sizes differ. On success the new listpack
+/// is returned along with the pointer to the replaced element.
+#[inline]
+pub fn replace<'a, A>(
+    allocator: &'a A,
+    lp: listpack,
+    p: element,
+    v: Value
+) -> Option<(listpack, element)>
+    where A: Allocator {
+    if lp.is_null() {
+        return None;
+    }
+    let size = total_bytes(allocator, lp);
+    replace_sized(allocator, lp, size, p, v).map(|(lp, p, _)| (lp, p))
+}
 
-                    // Write EOF
-                    *lp.offset((new_listpack_bytes as isize) - 1) = EOF;
-                }
-            }
-        } else if delta < 0 {
-            // Shift next element by back byte "delta" bytes.
-            // We must do this before resizing the allocation since it would
-            // result in data loss.
-            match next(lp, p) {
-                Some(ele) => {
-                    let ele_uintptr = ele as usize;
-                    if ele_uintptr <= p_uintptr {
-                        return None;
-                    }
+/// Like `replace()` for a listpack of 'size' bytes. The new size is returned
+/// along with the listpack and the replaced element.
+pub fn replace_sized<'a, A>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize,
+    mut p: element,
+    v: Value
+) -> Option<(listpack, element, usize)>
+    where A: Allocator {
+    unsafe {
+        // Let's try to be somewhat safe.
+        if lp.is_null() || p.is_null() || *p == EOF {
+            return None;
+        }
 
-                    let eleoff = ele_uintptr - lp_uintptr;
+        let old_listpack_bytes = size;
+        if !is_valid_element(lp, p, old_listpack_bytes) {
+            // Whoops!!! "p" is not within this listpack!
+            return None;
+        }
 
-                    std::ptr::copy(
-                        lp.offset(eleoff as isize),
-                        lp.offset((eleoff as isize) + delta),
-                        old_listpack_bytes as usize - eleoff,
-                    );
+        let encoded_size = v.size_for_write() as usize;
+        let old_size = {
+            let size = get_encoded_size(p);
+            (size + backlen_size(size as u64)) as usize
+        };
 
-                    v.encode(p, encoded_size);
-                }
-                None => {
-                    // Treat it as EOF
-                    v.encode(p, encoded_size);
+        // Offsets of 'p' and of the element after it, so that we can obtain
+        // their address again after a reallocation.
+        let poff = (p as usize) - (lp as usize);
+        let tailoff = poff + old_size;
 
-                    // Write EOF
-                    *lp.offset((new_listpack_bytes as isize) - 1) = EOF;
-                }
-            }
+        // Calculate the new size.
+        let new_listpack_bytes = old_listpack_bytes - old_size + encoded_size;
+        if new_listpack_bytes > u32::MAX as usize {
+            return None;
+        }
 
-            // Reduce allocation.
-            lp = allocator.realloc(lp, new_listpack_bytes);
+        if encoded_size > old_size {
+            // Grow allocation. We must do this before the shift since
+            // it could potentially overflow the actual allocation.
+            lp = allocator.realloc(lp, old_listpack_bytes, new_listpack_bytes);
             if lp.is_null() {
                 return None;
             }
+            p = lp.add(poff);
+        }
 
-            // Update "p" to it's pointer in the potentially new allocation.
-            p = lp.offset(poff as isize);
+        if encoded_size != old_size {
+            // Shift the next elements, EOF included.
+            std::ptr::copy(
+                lp.add(tailoff),
+                lp.add(poff + encoded_size),
+                old_listpack_bytes - tailoff,
+            );
         }
 
-        // Update bytes
-        set_total_bytes(lp, new_listpack_bytes as u32);
+        // Write value.
+        v.encode(p, encoded_size as u32);
 
-        Some((lp, p))
+        if encoded_size < old_size {
+            // Reduce allocation now that nothing is lost.
+            lp = shrink(allocator, lp, old_listpack_bytes, new_listpack_bytes);
+            p = lp.add(poff);
+        }
+
+        if allocator.has_header() {
+            // Update bytes
+            set_total_bytes(lp, new_listpack_bytes as u32);
+        }
+
+        Some((lp, p, new_listpack_bytes))
     }
 }
 
@@ -1563,22 +2252,35 @@ pub fn replace_string<'a, A, S>(
 #[inline]
 pub fn append<'a, A>(
     allocator: &'a A,
-    mut lp: listpack,
+    lp: listpack,
     v: Value
 ) -> Option<listpack>
+    where A: Allocator {
+    let size = total_bytes(allocator, lp);
+    append_sized(allocator, lp, size, v).map(|(lp, _)| lp)
+}
+
+/// Like `append()` for a listpack of 'size' bytes. The new size is returned
+/// along with the listpack.
+pub fn append_sized<'a, A>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize,
+    v: Value
+) -> Option<(listpack, usize)>
     where A: Allocator {
     unsafe {
-        let encoded_size = v.size_for_write();
+        let encoded_size = v.size_for_write() as usize;
 
         // Calculate the old and new sizes.
-        let old_listpack_bytes = get_total_bytes(lp);
+        let old_listpack_bytes = size;
         let new_listpack_bytes = old_listpack_bytes + encoded_size;
-        if new_listpack_bytes > u32::max_value() {
+        if new_listpack_bytes > u32::max_value() as usize {
             return None;
         }
 
         // realloc to make room
-        lp = allocator.realloc(lp, new_listpack_bytes as usize);
+        lp = allocator.realloc(lp, old_listpack_bytes, new_listpack_bytes);
         if lp.is_null() {
             return None;
         }
@@ -1589,7 +2291,7 @@ pub fn append<'a, A>(
         // Write value.
         // This overwrites the EOF byte at the end which will get added
         // immediately after this new value.
-        v.encode(p, encoded_size);
+        v.encode(p, encoded_size as u32);
 
         // Write EOF
         *lp.offset(new_listpack_bytes as isize - 1) = EOF;
@@ -1600,10 +2302,10 @@ pub fn append<'a, A>(
             if num_elements != HDR_NUMELE_UNKNOWN {
                 set_num_elements(lp, num_elements + 1);
             }
-            set_total_bytes(lp, new_listpack_bytes);
+            set_total_bytes(lp, new_listpack_bytes as u32);
         }
 
-        Some(lp)
+        Some((lp, new_listpack_bytes))
     }
 }
 
@@ -1642,63 +2344,354 @@ pub fn append_string<'a, A, S>(
     append(allocator, lp, v.as_value())
 }
 
-///
-pub fn delete<'a, A>(
+/// Append all the values yielded by 'values' to the tail of the listpack.
+/// The encoded size of the whole batch is computed upfront so the allocation
+/// grows only once, then every value is encoded sequentially. If the
+/// resulting listpack would be larger than the maximum listpack size nothing
+/// is appended and None is returned.
+pub fn append_many<'a, A, I>(
+    allocator: &'a A,
+    lp: listpack,
+    values: I
+) -> Option<listpack>
+    where A: Allocator, I: IntoIterator<Item = Value> {
+    let size = total_bytes(allocator, lp);
+    append_many_sized(allocator, lp, size, values).map(|(lp, _)| lp)
+}
+
+/// Like `append_many()` for a listpack of 'size' bytes. The new size is
+/// returned along with the listpack.
+pub fn append_many_sized<'a, A, I>(
     allocator: &'a A,
     mut lp: listpack,
+    size: usize,
+    values: I
+) -> Option<(listpack, usize)>
+    where A: Allocator, I: IntoIterator<Item = Value> {
+    unsafe {
+        let values: Vec<(Value, u32)> = values
+            .into_iter()
+            .map(|v| {
+                let encoded_size = v.size_for_write();
+                (v, encoded_size)
+            })
+            .collect();
+
+        // Calculate the old and new sizes.
+        let old_listpack_bytes = size;
+        let batch_bytes: u64 = values
+            .iter()
+            .map(|&(_, encoded_size)| encoded_size as u64)
+            .sum();
+        let new_listpack_bytes = old_listpack_bytes as u64 + batch_bytes;
+        if new_listpack_bytes > u32::MAX as u64 {
+            return None;
+        }
+
+        if values.is_empty() {
+            return Some((lp, old_listpack_bytes));
+        }
+
+        // realloc once to make room for the whole batch
+        lp = allocator.realloc(lp, old_listpack_bytes, new_listpack_bytes as usize);
+        if lp.is_null() {
+            return None;
+        }
+
+        // Start writing over the EOF marker.
+        let mut p = lp.offset(old_listpack_bytes as isize - 1);
+        for &(ref v, encoded_size) in &values {
+            v.encode(p, encoded_size);
+            p = p.offset(encoded_size as isize);
+        }
+
+        // Write EOF
+        *p = EOF;
+
+        if allocator.has_header() {
+            // Update header
+            let num_elements = get_num_elements(lp);
+            if num_elements != HDR_NUMELE_UNKNOWN {
+                let count = num_elements as usize + values.len();
+                if count < HDR_NUMELE_UNKNOWN as usize {
+                    set_num_elements(lp, count as u16);
+                } else {
+                    set_num_elements(lp, HDR_NUMELE_UNKNOWN);
+                }
+            }
+            set_total_bytes(lp, new_listpack_bytes as u32);
+        }
+
+        Some((lp, new_listpack_bytes as usize))
+    }
+}
+
+/// Delete the element pointed by 'p'. On success the new listpack is
+/// returned along with the element that took the place of the deleted one,
+/// or the new last element if the tail was deleted.
+pub fn delete<'a, A>(
+    allocator: &'a A,
+    lp: listpack,
     p: element
 ) -> Option<(listpack, element)>
     where A: Allocator {
     unsafe {
-        let encoded_size = get_encoded_size(p);
-        let backlen_size = backlen_size(encoded_size as u64);
-        let entry_size = (encoded_size + backlen_size) as usize;
-
-        let newp = match next(lp, p) {
-            Some(ele) => ele,
-            None => {
-                let old_listpack_bytes = get_total_bytes(lp);
-                if old_listpack_bytes == (HDR_USIZE as u32) + 1 {
-                    return None
-                }
+        if p.is_null() || *p == EOF {
+            return None;
+        }
 
-                let new_listpack_bytes = old_listpack_bytes - encoded_size - backlen_size;
-                lp = allocator.realloc(lp, new_listpack_bytes as usize);
-                *lp.offset(new_listpack_bytes as isize - 1) = EOF;
+        let (lp, p) = delete_range_with_entry(allocator, lp, p, 1)?;
+        if *p == EOF {
+            // The tail was deleted.
+            Some((
+                lp,
+                prev(lp, p).unwrap_or(std::ptr::null_mut())
+            ))
+        } else {
+            Some((lp, p))
+        }
+    }
+}
 
-                return Some((
-                    lp,
-                    last(lp).unwrap_or(std::ptr::null_mut())
-                ))
-            }
-        };
+/// Delete 'count' elements starting at the element pointed by 'p', or all
+/// the elements up to the tail if there are fewer. The remaining elements
+/// are moved with a single memmove and the allocation is shrunk once. On
+/// success the new listpack is returned along with a pointer to the element
+/// that now sits where 'p' was, which is the EOF byte when the range reached
+/// the tail.
+pub fn delete_range_with_entry<'a, A>(
+    allocator: &'a A,
+    lp: listpack,
+    p: element,
+    count: u32
+) -> Option<(listpack, element)>
+    where A: Allocator {
+    let size = total_bytes(allocator, lp);
+    delete_range_with_entry_sized(allocator, lp, size, p, count).map(|(lp, p, _)| (lp, p))
+}
+
+/// Like `delete_range_with_entry()` for a listpack of 'size' bytes. The new
+/// size is returned along with the listpack and the element where 'p' was.
+pub fn delete_range_with_entry_sized<'a, A>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize,
+    p: element,
+    count: u32
+) -> Option<(listpack, element, usize)>
+    where A: Allocator {
+    unsafe {
+        let old_listpack_bytes = size;
+        if p.is_null() || !is_valid_element(lp, p, old_listpack_bytes) {
+            return None;
+        }
 
-        let p_ptr = p as usize;
-        let lp_ptr = lp as usize;
-        if p_ptr < lp_ptr {
-            return None
+        // Find the end of the range.
+        let mut tail = p;
+        let mut deleted = 0u32;
+        while deleted < count && *tail != EOF {
+            tail = skip(tail);
+            deleted += 1;
         }
 
-        let poff = p_ptr - lp_ptr;
-        let old_listpack_bytes = get_total_bytes(lp);
-        let new_listpack_bytes = old_listpack_bytes - (entry_size as u32);
+        if deleted == 0 {
+            return Some((lp, p, old_listpack_bytes));
+        }
 
+        let poff = (p as usize) - (lp as usize);
+        let tailoff = (tail as usize) - (lp as usize);
+        let new_listpack_bytes = old_listpack_bytes - (tailoff - poff);
+
+        // Move the rest of the listpack, EOF included, over the range.
         std::ptr::copy(
-            lp.offset((poff as isize) + (entry_size as isize)),
-            lp.offset(poff as isize),
-            (old_listpack_bytes as usize) - (poff + entry_size));
+            tail,
+            p,
+            old_listpack_bytes - tailoff,
+        );
+
+        // Resize allocation down.
+        lp = shrink(allocator, lp, old_listpack_bytes, new_listpack_bytes);
+
+        if allocator.has_header() {
+            // Update header
+            let num_elements = get_num_elements(lp);
+            if num_elements != HDR_NUMELE_UNKNOWN {
+                set_num_elements(lp, num_elements - deleted as u16);
+            }
+            set_total_bytes(lp, new_listpack_bytes as u32);
+        }
+
+        Some((lp, lp.add(poff), new_listpack_bytes))
+    }
+}
+
+/// Delete 'count' elements starting at the element at 'index', which may be
+/// negative to count from the tail like in `seek()`. None is returned if the
+/// index is out of range.
+pub fn delete_range<'a, A>(
+    allocator: &'a A,
+    lp: listpack,
+    index: isize,
+    count: u32
+) -> Option<listpack>
+    where A: Allocator {
+    let p = seek(lp, index)?;
+    delete_range_with_entry(allocator, lp, p, count).map(|(lp, _)| lp)
+}
+
+/// Delete all the elements pointed by 'ps' in a single compaction pass. The
+/// pointers must belong to the listpack and be sorted by address, without
+/// duplicates, otherwise nothing is deleted and None is returned.
+pub fn batch_delete<'a, A>(
+    allocator: &'a A,
+    lp: listpack,
+    ps: &[element]
+) -> Option<listpack>
+    where A: Allocator {
+    if ps.is_empty() {
+        return Some(lp);
+    }
+    let size = total_bytes(allocator, lp);
+    batch_delete_sized(allocator, lp, size, ps).map(|(lp, _)| lp)
+}
+
+/// Like `batch_delete()` for a listpack of 'size' bytes. The new size is
+/// returned along with the listpack.
+pub fn batch_delete_sized<'a, A>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize,
+    ps: &[element]
+) -> Option<(listpack, usize)>
+    where A: Allocator {
+    if ps.is_empty() {
+        return Some((lp, size));
+    }
+
+    unsafe {
+        let old_listpack_bytes = size;
+
+        // Validate everything before moving any byte.
+        let mut min = lp.offset(HDR_SIZE);
+        for &p in ps {
+            if p < min || !is_valid_element(lp, p, old_listpack_bytes) || *p == EOF {
+                return None;
+            }
+            min = skip(p);
+        }
+
+        // Every chunk of elements between two deleted elements is moved
+        // towards the head right after the previously kept chunk.
+        let end = lp.add(old_listpack_bytes);
+        let mut dst = ps[0];
+        for (i, &p) in ps.iter().enumerate() {
+            let src = skip(p);
+            let src_end = match ps.get(i + 1) {
+                Some(&p) => p,
+                None => end
+            };
+            let len = (src_end as usize) - (src as usize);
+            std::ptr::copy(src, dst, len);
+            dst = dst.add(len);
+        }
+
+        let new_listpack_bytes = (dst as usize) - (lp as usize);
+
+        // Resize allocation down.
+        lp = shrink(allocator, lp, old_listpack_bytes, new_listpack_bytes);
+
+        if allocator.has_header() {
+            // Update header
+            let num_elements = get_num_elements(lp);
+            if num_elements != HDR_NUMELE_UNKNOWN {
+                set_num_elements(lp, num_elements - ps.len() as u16);
+            }
+            set_total_bytes(lp, new_listpack_bytes as u32);
+        }
+
+        Some((lp, new_listpack_bytes))
+    }
+}
+
+/// Rewrite every element of 'lp' in its most compact encoding: strings that
+/// are the canonical representation of an integer become integers, like
+/// Redis encodes them, and integers and string lengths written wider than
+/// needed by other writers are narrowed. Elements only shrink, so they are
+/// moved over in a single pass followed by one reallocation, which may fail
+/// without losing anything. Returns the listpack and the number of bytes
+/// saved.
+///
+/// Binary values written with `Str`, like floats, can't be told apart from
+/// other strings and are left alone.
+pub fn optimize<'a, A>(
+    allocator: &'a A,
+    lp: listpack
+) -> (listpack, usize)
+    where A: Allocator {
+    let size = total_bytes(allocator, lp);
+    let (lp, new_size) = optimize_sized(allocator, lp, size);
+    (lp, size - new_size)
+}
+
+/// Like `optimize()` for a listpack of 'size' bytes, returning the new size
+/// instead of the bytes saved.
+pub fn optimize_sized<'a, A>(
+    allocator: &'a A,
+    mut lp: listpack,
+    size: usize
+) -> (listpack, usize)
+    where A: Allocator {
+    unsafe {
+        let old_listpack_bytes = size;
+        let mut src = lp.offset(HDR_SIZE);
+        let mut dst = src;
+
+        while *src != EOF {
+            let encoded_size = get_encoded_size(src);
+            let entry_size = (encoded_size + backlen_size(encoded_size as u64)) as usize;
+            let value = match get(src) {
+                Value::String(ele, size) => Value::compact(std::slice::from_raw_parts(ele, size as usize)),
+                value => value
+            };
+
+            let new_encoded_size = value.encoded_size();
+            if new_encoded_size == encoded_size {
+                // Already compact, only moved.
+                if dst != src {
+                    ptr::copy(src, dst, entry_size);
+                }
+            } else if let Value::String(ele, size) = value {
+                // The string goes first as its new header could overlap it.
+                let front = string_front_len(size) as usize;
+                ptr::copy(ele, dst.add(front), size as usize);
+                encode_string_front(dst, size);
+                Value::encode_backlen(dst.add(new_encoded_size as usize), new_encoded_size);
+            } else {
+                let mut buf = [0u8; MAX_INT_ENCODING_LEN + 1];
+                let size = value.size_for_write();
+                value.encode(buf.as_mut_ptr(), size);
+                ptr::copy_nonoverlapping(buf.as_ptr(), dst, size as usize);
+            }
+
+            src = src.add(entry_size);
+            dst = dst.add(value.size_for_write() as usize);
+        }
+
+        if dst == src {
+            return (lp, old_listpack_bytes);
+        }
+        *dst = EOF;
+
+        let new_listpack_bytes = (dst as usize) - (lp as usize) + 1;
 
         // Resize allocation down.
-        lp = allocator.realloc(lp, new_listpack_bytes as usize);
+        lp = shrink(allocator, lp, old_listpack_bytes, new_listpack_bytes);
 
-        // Update header
-        let num_elements = get_num_elements(lp);
-        if num_elements != HDR_NUMELE_UNKNOWN {
-            set_num_elements(lp, num_elements - 1);
+        if allocator.has_header() {
+            set_total_bytes(lp, new_listpack_bytes as u32);
         }
-        set_total_bytes(lp, new_listpack_bytes);
 
-        Some((lp, p))
+        (lp, new_listpack_bytes)
     }
 }
 
@@ -2099,16 +3092,6 @@ impl From<Value> for f64 {
     }
 }
 
-impl Into<Value> for u128 {
-    #[inline]
-    fn into(self) -> Value {
-        Value::String(
-            &self as *const _ as *const u8,
-            size_of::<u128>() as u32,
-        )
-    }
-}
-
 impl From<Value> for u128 {
     fn from(v: Value) -> Self {
         match &v {
@@ -2127,16 +3110,6 @@ impl From<Value> for u128 {
     }
 }
 
-impl Into<Value> for i128 {
-    #[inline]
-    fn into(self) -> Value {
-        Value::String(
-            &self as *const _ as *const u8,
-            std::mem::size_of::<i128>() as u32,
-        )
-    }
-}
-
 impl From<Value> for i128 {
     fn from(v: Value) -> Self {
         match &v {
@@ -2176,27 +3149,55 @@ impl<'a> Into<Value> for &'a String {
     }
 }
 
-impl Into<Value> for String {
+impl<'a> Into<Value> for &'a Vec<u8> {
     #[inline]
     fn into(self) -> Value {
         Value::String(self.as_ptr(), self.len() as u32)
     }
 }
 
-impl<'a> Into<Value> for &'a Vec<u8> {
+/// A value that can be written to a listpack. Unlike `Into<Value>` it is
+/// borrowed while the listpack encodes it, so the bytes of an owned `String`,
+/// `Vec<u8>` or 128 bit integer are still alive when they are copied.
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+impl<T: Into<Value> + Clone> ToValue for T {
     #[inline]
-    fn into(self) -> Value {
+    fn to_value(&self) -> Value {
+        self.clone().into()
+    }
+}
+
+impl ToValue for String {
+    #[inline]
+    fn to_value(&self) -> Value {
         Value::String(self.as_ptr(), self.len() as u32)
     }
 }
 
-impl Into<Value> for Vec<u8> {
+impl ToValue for Vec<u8> {
     #[inline]
-    fn into(self) -> Value {
+    fn to_value(&self) -> Value {
         Value::String(self.as_ptr(), self.len() as u32)
     }
 }
 
+impl ToValue for u128 {
+    #[inline]
+    fn to_value(&self) -> Value {
+        Value::String(self as *const _ as *const u8, size_of::<u128>() as u32)
+    }
+}
+
+impl ToValue for i128 {
+    #[inline]
+    fn to_value(&self) -> Value {
+        Value::String(self as *const _ as *const u8, size_of::<i128>() as u32)
+    }
+}
+
 
 pub trait Int: Sync + Send + Sized + Clone + Default + std::fmt::Debug {
     fn to_int64(self) -> i64;
@@ -2536,6 +3537,592 @@ mod tests {
 
             println!("Bytes:  {}", get_total_bytes(lp));
             println!("Length: {}", get_num_elements(lp));
+            ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+        }
+    }
+
+    #[test]
+    fn test_string_to_int() {
+        assert_eq!(string_to_int(b"0"), Some(0));
+        assert_eq!(string_to_int(b"-1"), Some(-1));
+        assert_eq!(string_to_int(b"1234"), Some(1234));
+        assert_eq!(string_to_int(b"9223372036854775807"), Some(i64::max_value()));
+        assert_eq!(string_to_int(b"-9223372036854775808"), Some(i64::min_value()));
+        assert_eq!(string_to_int(b"9223372036854775808"), None);
+        assert_eq!(string_to_int(b""), None);
+        assert_eq!(string_to_int(b"-"), None);
+        assert_eq!(string_to_int(b"-0"), None);
+        assert_eq!(string_to_int(b"01"), None);
+        assert_eq!(string_to_int(b"+1"), None);
+        assert_eq!(string_to_int(b" 1"), None);
+        assert_eq!(string_to_int(b"1a"), None);
+    }
+
+    #[test]
+    fn test_value_cmp() {
+        use std::cmp::Ordering;
+        use std::collections::HashSet;
+
+        assert!(Value::Int(10) == "10".into());
+        assert!(Value::Int(10) != "010".into());
+        let abc: Value = "abc".into();
+        assert!(abc == "abc".into());
+        assert!(abc != "abd".into());
+
+        assert_eq!(Value::Int(-5).cmp(&"3".into()), Ordering::Less);
+        assert_eq!(Value::Int(100).cmp(&"a".into()), Ordering::Less);
+        assert_eq!(abc.cmp(&"abb".into()), Ordering::Greater);
+        assert_eq!(abc.cmp(&"abcd".into()), Ordering::Less);
+
+        let mut set = HashSet::new();
+        set.insert(Value::Int(42));
+        assert!(set.contains(&"42".into()));
+        assert!(!set.contains(&"42.0".into()));
+    }
+
+    #[test]
+    fn test_compare() {
+        let mut lp = new(ALLOCATOR);
+        lp = append(ALLOCATOR, lp, Value::Int(-300)).unwrap();
+        lp = append(ALLOCATOR, lp, "hello".into()).unwrap();
+
+        let p = first(lp).unwrap();
+        assert!(compare(p, b"-300"));
+        assert!(!compare(p, b"300"));
+        assert!(!compare(p, b"hello"));
+
+        let p = next(lp, p).unwrap();
+        assert!(compare(p, b"hello"));
+        assert!(!compare(p, b"hell"));
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_find() {
+        let mut lp = new(ALLOCATOR);
+        let long = "x".repeat(300);
+        lp = append(ALLOCATOR, lp, "a".into()).unwrap();
+        lp = append(ALLOCATOR, lp, Value::Int(1)).unwrap();
+        lp = append(ALLOCATOR, lp, Value::Int(1000)).unwrap();
+        lp = append(ALLOCATOR, lp, "b".into()).unwrap();
+        lp = append(ALLOCATOR, lp, long.as_str().into()).unwrap();
+        lp = append(ALLOCATOR, lp, "c".into()).unwrap();
+
+        let head = first(lp).unwrap();
+
+        let p = find(lp, head, b"1000", 1).unwrap();
+        assert_eq!(get_int(p), 1000);
+        assert_eq!(get_str(next(lp, p).unwrap()), "b");
+
+        // "1" is a value, it is skipped with a stride of 1.
+        assert!(find(lp, head, b"1", 1).is_none());
+        assert_eq!(get_int(find(lp, head, b"1", 0).unwrap()), 1);
+
+        let p = find(lp, head, long.as_bytes(), 1).unwrap();
+        assert_eq!(get_str(p), long.as_str());
+        assert_eq!(get_str(next(lp, p).unwrap()), "c");
+
+        assert!(find(lp, head, b"d", 0).is_none());
+        assert!(find(lp, head, b"01000", 0).is_none());
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_append_many() {
+        let mut lp = new(ALLOCATOR);
+        lp = append(ALLOCATOR, lp, Value::Int(0)).unwrap();
+
+        let long = "y".repeat(5000);
+        let values = vec![
+            Value::Int(1),
+            "two".into(),
+            Value::Int(-30000),
+            long.as_str().into(),
+            Value::Int(i64::MAX),
+        ];
+        lp = append_many(ALLOCATOR, lp, values.iter().cloned()).unwrap();
+
+        assert_eq!(length(lp), 6);
+        let expected_bytes = 6 + 2 + values.iter()
+            .map(|v| v.size_for_write())
+            .sum::<u32>() + 1;
+        assert_eq!(get_total_bytes(lp), expected_bytes);
+
+        // Walk backwards to check every backlen.
+        let mut p = last(lp).unwrap();
+        for v in values.iter().rev() {
+            assert!(get(p) == *v);
+            p = prev(lp, p).unwrap();
+        }
+        assert_eq!(get_int(p), 0);
+        assert!(prev(lp, p).is_none());
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    fn ints(lp: listpack) -> Vec<i64> {
+        let mut v = Vec::new();
+        let mut p = first(lp);
+        while let Some(ele) = p {
+            v.push(get_int(ele));
+            p = next(lp, ele);
+        }
+        v
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut lp = new(ALLOCATOR);
+        lp = append_many(ALLOCATOR, lp, (0..5).map(Value::Int)).unwrap();
+
+        let (lp, p) = delete(ALLOCATOR, lp, seek(lp, 1).unwrap()).unwrap();
+        assert_eq!(get_int(p), 2);
+        let (lp, p) = delete(ALLOCATOR, lp, last(lp).unwrap()).unwrap();
+        assert_eq!(get_int(p), 3);
+        assert_eq!(ints(lp), vec![0, 2, 3]);
+        assert_eq!(length(lp), 3);
+        assert_eq!(get_total_bytes(lp), 6 + 3 * 2 + 1);
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut lp = new(ALLOCATOR);
+        lp = append_many(ALLOCATOR, lp, (0..10).map(Value::Int)).unwrap();
+
+        lp = delete_range(ALLOCATOR, lp, 2, 3).unwrap();
+        assert_eq!(ints(lp), vec![0, 1, 5, 6, 7, 8, 9]);
+
+        lp = delete_range(ALLOCATOR, lp, -2, 10).unwrap();
+        assert_eq!(ints(lp), vec![0, 1, 5, 6, 7]);
+        assert!(delete_range(ALLOCATOR, lp, 5, 1).is_none());
+
+        let (lp, p) = delete_range_with_entry(ALLOCATOR, lp, first(lp).unwrap(), 2).unwrap();
+        assert_eq!(get_int(p), 5);
+        assert_eq!(ints(lp), vec![5, 6, 7]);
+        assert_eq!(length(lp), 3);
+        assert_eq!(get_total_bytes(lp), 6 + 3 * 2 + 1);
+        assert_eq!(get_int(last(lp).unwrap()), 7);
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_batch_delete() {
+        let mut lp = new(ALLOCATOR);
+        lp = append_many(ALLOCATOR, lp, (0..10).map(Value::Int)).unwrap();
+
+        let ps: Vec<element> = [0, 3, 4, 9].iter()
+            .map(|&i| seek(lp, i).unwrap())
+            .collect();
+        lp = batch_delete(ALLOCATOR, lp, &ps).unwrap();
+        assert_eq!(ints(lp), vec![1, 2, 5, 6, 7, 8]);
+        assert_eq!(length(lp), 6);
+        assert_eq!(get_int(last(lp).unwrap()), 8);
+
+        // Unsorted pointers are rejected.
+        let ps = [seek(lp, 2).unwrap(), seek(lp, 1).unwrap()];
+        assert!(batch_delete(ALLOCATOR, lp, &ps).is_none());
+        assert_eq!(length(lp), 6);
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut lp = new(ALLOCATOR);
+        let long = "x".repeat(5000);
+        lp = append_many(ALLOCATOR, lp, vec![
+            Value::Int(1),
+            Value::Int(-5000),
+            Value::Int(i64::MIN),
+            "hello".into(),
+            (&*long).into(),
+        ]).unwrap();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(lp, get_total_bytes(lp) as usize).to_vec()
+        };
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+        assert!(validate(&bytes));
+
+        // Truncated, wrong size, bad encoding, bad backlen and bad count.
+        assert!(!validate(&bytes[..bytes.len() - 1]));
+        assert!(!validate(&bytes[..6]));
+        let mut bad = bytes.clone();
+        bad[0] += 1;
+        assert!(!validate(&bad));
+        let mut bad = bytes.clone();
+        bad[6] = 0xF5;
+        assert!(!validate(&bad));
+        let mut bad = bytes.clone();
+        bad[7] = 3;
+        assert!(!validate(&bad));
+        let mut bad = bytes.clone();
+        bad[4] = 4;
+        assert!(!validate(&bad));
+        bad[4] = 0xFF;
+        bad[5] = 0xFF;
+        assert!(validate(&bad));
+        // A string length running past the end.
+        let mut bad = bytes.clone();
+        let at = bad.len() - 5000 - 4;
+        bad[at] = 0xFF;
+        assert!(!validate(&bad));
+    }
+
+    /// Allocator checking that the sizes it's given are those of the live
+    /// allocations, so layouts always match.
+    struct CheckedAllocator {
+        /// Size the listpack gives to every live allocation, and its real
+        /// size, which is larger after a failed shrink.
+        live: std::cell::RefCell<std::collections::HashMap<usize, (usize, usize)>>,
+        header: bool,
+        fail_shrinks: std::cell::Cell<bool>,
+    }
+
+    impl CheckedAllocator {
+        fn new() -> CheckedAllocator {
+            CheckedAllocator { live: Default::default(), header: true, fail_shrinks: Default::default() }
+        }
+
+        fn headerless() -> CheckedAllocator {
+            CheckedAllocator { live: Default::default(), header: false, fail_shrinks: Default::default() }
+        }
+
+        fn check(&self, lp: listpack, size: usize) -> usize {
+            let live = self.live.borrow();
+            let &(listpack_size, real_size) = live.get(&(lp as usize)).expect("not a live allocation");
+            assert_eq!(listpack_size, size);
+            real_size
+        }
+    }
+
+    impl Allocator for CheckedAllocator {
+        fn has_header(&self) -> bool {
+            self.header
+        }
+
+        fn alloc(&self, size: usize) -> listpack {
+            let lp = DefaultAllocator.alloc(size);
+            self.live.borrow_mut().insert(lp as usize, (size, size));
+            lp
+        }
+
+        fn realloc(&self, lp: listpack, old_size: usize, new_size: usize) -> listpack {
+            let real_size = self.check(lp, old_size);
+            if new_size < old_size && self.fail_shrinks.get() {
+                self.live.borrow_mut().insert(lp as usize, (new_size, real_size));
+                return std::ptr::null_mut();
+            }
+            self.live.borrow_mut().remove(&(lp as usize));
+            let lp = DefaultAllocator.realloc(lp, real_size, new_size);
+            self.live.borrow_mut().insert(lp as usize, (new_size, new_size));
+            lp
+        }
+
+        fn dealloc(&self, lp: listpack, size: usize) {
+            let real_size = self.check(lp, size);
+            self.live.borrow_mut().remove(&(lp as usize));
+            DefaultAllocator.dealloc(lp, real_size)
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut lp = new(ALLOCATOR);
+        let long = "x".repeat(100);
+        lp = append_many(ALLOCATOR, lp, vec![
+            Value::Int(1),
+            Value::Int(2),
+            Value::Int(-5000),
+            Value::Int(i64::MIN),
+            "hello".into(),
+            "-5000".into(),
+            (&*long).into(),
+        ]).unwrap();
+
+        let stats = stats(lp);
+        assert_eq!(stats.total_bytes, get_total_bytes(lp) as usize);
+        assert_eq!(stats.entries, 7);
+        assert_eq!(stats.header_bytes, 7);
+        assert_eq!(stats.encoding(Encoding::Uint7Bit), EncodingStats { entries: 2, bytes: 2 });
+        assert_eq!(stats.encoding(Encoding::Int16Bit), EncodingStats { entries: 1, bytes: 3 });
+        assert_eq!(stats.encoding(Encoding::Int64Bit), EncodingStats { entries: 1, bytes: 9 });
+        assert_eq!(stats.encoding(Encoding::Str6Bit), EncodingStats { entries: 2, bytes: 6 + 6 });
+        assert_eq!(stats.encoding(Encoding::Str12Bit), EncodingStats { entries: 1, bytes: 102 });
+        assert_eq!(stats.encoding(Encoding::Int13Bit), EncodingStats::default());
+        assert_eq!(stats.backlen_bytes, 7);
+        assert_eq!(stats.entry_bytes(), 2 + 3 + 9 + 12 + 102 + 7);
+        assert_eq!(stats.average_entry_size(), stats.entry_bytes() as f64 / 7.0);
+
+        // "-5000" takes 6 + 1 bytes as a string and 3 + 1 as an integer.
+        assert_eq!(stats.int_strings, 1);
+        assert_eq!(stats.int_string_savings, 3);
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+        let lp = new(ALLOCATOR);
+        assert_eq!(super::stats(lp).entries, 0);
+        assert_eq!(super::stats(lp).average_entry_size(), 0.0);
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_optimize() {
+        let mut lp = new(ALLOCATOR);
+        let long = "y".repeat(100);
+        let mut float = 1.5f64;
+        lp = append_many(ALLOCATOR, lp, vec![
+            "12".into(),
+            Value::Int(-5000),
+            "hello".into(),
+            "-5000".into(),
+            (&*long).into(),
+            "9223372036854775807".into(),
+            "007".into(),
+            float.as_value(),
+        ]).unwrap();
+        // A 6 bit string and a 64 bit integer written wider than needed.
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(lp, get_total_bytes(lp) as usize).to_vec()
+        };
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+        let wide = [0xE0, 2, b'h', b'i', 4, 0xF4, 3, 0, 0, 0, 0, 0, 0, 0, 9];
+        let eof = bytes.len() - 1;
+        bytes.splice(eof..eof, wide.iter().cloned());
+        set_total_bytes(bytes.as_mut_ptr(), bytes.len() as u32);
+        set_num_elements(bytes.as_mut_ptr(), 10);
+        assert!(validate(&bytes));
+
+        let expected_savings = stats(bytes.as_mut_ptr()).int_string_savings + 1 + 8;
+        let lp = ALLOCATOR.alloc(bytes.len());
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), lp, bytes.len()) };
+        let (lp, saved) = optimize(ALLOCATOR, lp);
+        assert_eq!(saved, expected_savings);
+        assert_eq!(get_total_bytes(lp) as usize, bytes.len() - expected_savings);
+        assert_eq!(length(lp), 10);
+        assert!(validate(unsafe {
+            std::slice::from_raw_parts(lp, get_total_bytes(lp) as usize)
+        }));
+
+        let values: Vec<OwnedValue> = {
+            let mut values = Vec::new();
+            let mut p = first(lp);
+            while let Some(ele) = p {
+                values.push(get(ele).to_owned_value());
+                p = next(lp, ele);
+            }
+            values
+        };
+        assert_eq!(values, vec![
+            OwnedValue::Int(12),
+            OwnedValue::Int(-5000),
+            OwnedValue::String(b"hello".to_vec()),
+            OwnedValue::Int(-5000),
+            OwnedValue::String(long.into_bytes()),
+            OwnedValue::Int(i64::MAX),
+            OwnedValue::String(b"007".to_vec()),
+            OwnedValue::String(1.5f64.to_bits().to_le_bytes().to_vec()),
+            OwnedValue::String(b"hi".to_vec()),
+            OwnedValue::Int(3),
+        ]);
+        assert_eq!(stats(lp).int_strings, 0);
+        assert_eq!(stats(lp).encoding(Encoding::Str12Bit).entries, 1);
+
+        // Nothing left to save.
+        let (lp, saved) = optimize(ALLOCATOR, lp);
+        assert_eq!(saved, 0);
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_allocator_sizes() {
+        let a = &CheckedAllocator::new();
+        let long = "y".repeat(300);
+
+        let mut lp = new(a);
+        lp = append(a, lp, Value::Int(1)).unwrap();
+        lp = append_many(a, lp, (2..6).map(Value::Int)).unwrap();
+        let (lp, p) = insert(a, lp, "a".into(), Placement::Before, first(lp).unwrap()).unwrap();
+        let (lp, _) = insert(a, lp, Value::Int(0), Placement::After, p).unwrap();
+        let (lp, _) = insert_many(a, lp, vec!["b".into(), "c".into()], Placement::After, last(lp).unwrap()).unwrap();
+
+        // Growing, shrinking and same size replacements.
+        let (lp, p) = replace(a, lp, first(lp).unwrap(), (&*long).into()).unwrap();
+        let (lp, p) = replace(a, lp, p, "z".into()).unwrap();
+        let (lp, _) = replace(a, lp, p, "a".into()).unwrap();
+        assert_eq!(get_str(first(lp).unwrap()), "a");
+
+        let (lp, _) = delete(a, lp, first(lp).unwrap()).unwrap();
+        let lp = delete_range(a, lp, 0, 2).unwrap();
+        let (lp, _) = delete_range_with_entry(a, lp, seek(lp, 1).unwrap(), 1).unwrap();
+        let ps = [first(lp).unwrap(), last(lp).unwrap()];
+        let lp = batch_delete(a, lp, &ps).unwrap();
+        assert_eq!(length(lp), 3);
+        assert_eq!(get_int(first(lp).unwrap()), 4);
+        assert_eq!(get_str(last(lp).unwrap()), "b");
+
+        a.dealloc(lp, get_total_bytes(lp) as usize);
+        assert!(a.live.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_failed_shrinks() {
+        let a = &CheckedAllocator::new();
+        let long = "y".repeat(300);
+        let mut lp = new(a);
+        lp = append_many(a, lp, (0..10).map(Value::Int)).unwrap();
+        lp = append(a, lp, (&*long).into()).unwrap();
+        a.fail_shrinks.set(true);
+
+        // The listpack stays where it is, and is complete and consistent.
+        let (lp, p) = replace(a, lp, last(lp).unwrap(), "short".into()).unwrap();
+        assert_eq!(get_str(p), "short");
+        let (lp, p) = delete_range_with_entry(a, lp, first(lp).unwrap(), 2).unwrap();
+        assert_eq!(get_int(p), 2);
+        let ps = [seek(lp, 1).unwrap(), seek(lp, 3).unwrap()];
+        let lp = batch_delete(a, lp, &ps).unwrap();
+        let lp = delete_range(a, lp, -1, 1).unwrap();
+        let lp = append(a, lp, "123".into()).unwrap();
+        let (lp, saved) = optimize(a, lp);
+        assert_eq!(saved, 3);
+
+        assert_eq!(ints(lp), vec![2, 4, 6, 7, 8, 9, 123]);
+        assert_eq!(length(lp), 7);
+        assert!(validate(unsafe {
+            std::slice::from_raw_parts(lp, get_total_bytes(lp) as usize)
+        }));
+
+        // Growing again goes through the allocator with the listpack's size.
+        a.fail_shrinks.set(false);
+        let lp = append(a, lp, Value::Int(10)).unwrap();
+        assert_eq!(get_int(last(lp).unwrap()), 10);
+        a.dealloc(lp, get_total_bytes(lp) as usize);
+        assert!(a.live.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_headerless_sizes() {
+        let a = &CheckedAllocator::headerless();
+        let long = "y".repeat(300);
+
+        // Nothing may trust the size in the header.
+        let lp = new(a);
+        set_total_bytes(lp, 3);
+        let mut size = HDR_USIZE + 1;
+
+        let (lp, new_size) = append_sized(a, lp, size, Value::Int(1)).unwrap();
+        size = new_size;
+        let (lp, new_size) = append_many_sized(a, lp, size, (2..6).map(Value::Int)).unwrap();
+        size = new_size;
+        let (lp, p, new_size) = insert_sized(a, lp, size, "a".into(), Placement::Before, first(lp).unwrap()).unwrap();
+        size = new_size;
+        let (lp, _, new_size) = insert_sized(a, lp, size, Value::Int(0), Placement::After, p).unwrap();
+        size = new_size;
+        let tail = prev(lp, unsafe { lp.add(size - 1) }).unwrap();
+        let (lp, p, new_size) = insert_sized(a, lp, size, Value::Int(9), Placement::After, tail).unwrap();
+        size = new_size;
+        assert_eq!(get_int(p), 9);
+        let (lp, _, new_size) = insert_many_sized(a, lp, size, vec!["b".into(), "c".into()], Placement::Before, p).unwrap();
+        size = new_size;
+        assert_eq!(total_bytes(a, lp), size);
+
+        // Growing, shrinking and same size replacements.
+        let (lp, p, new_size) = replace_sized(a, lp, size, first(lp).unwrap(), (&*long).into()).unwrap();
+        size = new_size;
+        let (lp, p, new_size) = replace_sized(a, lp, size, p, "z".into()).unwrap();
+        size = new_size;
+        let (lp, _, new_size) = replace_sized(a, lp, size, p, "123".into()).unwrap();
+        size = new_size;
+        assert_eq!(total_bytes(a, lp), size);
+
+        let (lp, _, new_size) = delete_range_with_entry_sized(a, lp, size, seek(lp, 1).unwrap(), 1).unwrap();
+        size = new_size;
+        let ps = [seek(lp, 1).unwrap(), seek(lp, 3).unwrap()];
+        let (lp, new_size) = batch_delete_sized(a, lp, size, &ps).unwrap();
+        size = new_size;
+        let (lp, new_size) = optimize_sized(a, lp, size);
+        // "123" became an integer.
+        assert_eq!(new_size, size - 3);
+        assert_eq!(total_bytes(a, lp), new_size);
+
+        // The functions without a size find it on their own.
+        let (lp, _) = replace(a, lp, first(lp).unwrap(), (&*long).into()).unwrap();
+        let lp = append(a, lp, Value::Int(10)).unwrap();
+        let (lp, _) = insert(a, lp, Value::Int(11), Placement::Before, first(lp).unwrap()).unwrap();
+        let (lp, _) = delete(a, lp, first(lp).unwrap()).unwrap();
+        let lp = delete_range(a, lp, 0, 1).unwrap();
+        let lp = batch_delete(a, lp, &[first(lp).unwrap()]).unwrap();
+        let (lp, saved) = optimize(a, lp);
+        assert_eq!(saved, 0);
+
+        let mut values = Vec::new();
+        let mut p = first(lp);
+        while let Some(ele) = p {
+            values.push(get(ele).to_owned_value());
+            p = next(lp, ele);
         }
+        assert_eq!(values, vec![
+            OwnedValue::Int(4),
+            OwnedValue::Int(5),
+            OwnedValue::String(b"b".to_vec()),
+            OwnedValue::String(b"c".to_vec()),
+            OwnedValue::Int(9),
+            OwnedValue::Int(10),
+        ]);
+        assert_eq!(length(lp), 6);
+        assert_eq!(get_total_bytes(lp), 3);
+
+        a.dealloc(lp, total_bytes(a, lp));
+        assert!(a.live.borrow().is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_insert_many() {
+        let mut lp = new(ALLOCATOR);
+        lp = append_many(ALLOCATOR, lp, vec![Value::Int(0), Value::Int(9)]).unwrap();
+
+        let target = first(lp).unwrap();
+        let (lp, p) = insert_many(ALLOCATOR, lp, (1..5).map(Value::Int), Placement::After, target).unwrap();
+        assert_eq!(get_int(p), 1);
+
+        let target = last(lp).unwrap();
+        let (lp, p) = insert_many(ALLOCATOR, lp, (5..9).map(Value::Int), Placement::Before, target).unwrap();
+        assert_eq!(get_int(p), 5);
+
+        let (lp, p) = insert_many(ALLOCATOR, lp, (10..12).map(Value::Int), Placement::After, std::ptr::null_mut()).unwrap();
+        assert_eq!(get_int(p), 10);
+
+        assert_eq!(ints(lp), (0..12).collect::<Vec<i64>>());
+        assert_eq!(length(lp), 12);
+        assert_eq!(get_int(prev(lp, last(lp).unwrap()).unwrap()), 10);
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut lp = new(ALLOCATOR);
+        lp = append_many(ALLOCATOR, lp, (0..3).map(Value::Int)).unwrap();
+
+        // Same size.
+        let (lp, p) = replace(ALLOCATOR, lp, seek(lp, 1).unwrap(), Value::Int(7)).unwrap();
+        assert_eq!(get_int(p), 7);
+
+        // Grow.
+        let (lp, p) = replace(ALLOCATOR, lp, p, "a longer string".into()).unwrap();
+        assert_eq!(get_str(p), "a longer string");
+        assert_eq!(get_int(next(lp, p).unwrap()), 2);
+
+        // Shrink.
+        let (lp, p) = replace(ALLOCATOR, lp, p, Value::Int(-1)).unwrap();
+        assert_eq!(get_int(p), -1);
+        assert_eq!(ints(lp), vec![0, -1, 2]);
+        assert_eq!(get_total_bytes(lp), 6 + 2 + 3 + 2 + 1);
+        assert_eq!(get_int(prev(lp, last(lp).unwrap()).unwrap()), -1);
+
+        ALLOCATOR.dealloc(lp, get_total_bytes(lp) as usize);
+    }
+}
@@ -92,11 +92,11 @@ impl ::raw::Allocator for SegmentWriter {
         unimplemented!()
     }
 
-    fn realloc(&self, lp: *mut u8, size: usize) -> *mut u8 {
+    fn realloc(&self, lp: *mut u8, _old_size: usize, _new_size: usize) -> *mut u8 {
         unimplemented!()
     }
 
-    fn dealloc(&self, lp: *mut u8) {
+    fn dealloc(&self, lp: *mut u8, size: usize) {
         unimplemented!()
     }
 }
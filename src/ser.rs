@@ -0,0 +1,492 @@
+//! Serialize Rust values into a listpack.
+//!
+//! Values are flattened into entries without any field names:
+//!
+//! - integers, bools and chars' code points become integer entries in the
+//!   most compact encoding, floats are stored by their bits like the
+//!   `Into<Value>` impls do, and 128 bit integers as 16 byte strings.
+//! - strings and bytes become string entries.
+//! - sequences, maps and structs are prefixed by their number of elements,
+//!   pairs or fields. Tuples have a fixed length and aren't prefixed.
+//! - `None` is a 0 entry and `Some` a 1 entry followed by the value.
+//! - enum variants start with the variant index.
+//!
+//! It also implements `Serialize` for `Listpack` and its values.
+
+use std;
+
+use serde::ser::{self, Serialize, SerializeSeq};
+
+use ::error::Error;
+use ::raw::*;
+use ::Listpack;
+
+/// Serialize 'value' into a new listpack.
+pub fn to_listpack<T: ?Sized + Serialize>(value: &T) -> Result<Listpack, Error> {
+    let mut serializer = Serializer::new();
+    value.serialize(&mut serializer)?;
+    Ok(serializer.into_listpack())
+}
+
+/// Serializer appending the entries to a listpack.
+pub struct Serializer {
+    lp: Listpack,
+}
+
+impl Serializer {
+    pub fn new() -> Serializer {
+        Serializer::with_listpack(Listpack::new())
+    }
+
+    /// Append to the entries of an existing listpack.
+    pub fn with_listpack(lp: Listpack) -> Serializer {
+        Serializer { lp }
+    }
+
+    #[inline]
+    pub fn into_listpack(self) -> Listpack {
+        self.lp
+    }
+
+    #[inline]
+    fn push(&mut self, v: Value) -> Result<(), Error> {
+        if self.lp.append_val(v) {
+            Ok(())
+        } else {
+            Err(Error::Alloc)
+        }
+    }
+
+    /// Append a placeholder for a length prefix and return its offset, since
+    /// element pointers don't survive reallocations.
+    fn push_prefix(&mut self) -> Result<usize, Error> {
+        let offset = get_total_bytes(self.lp.0) as usize - 1;
+        self.push(Value::Int(0))?;
+        Ok(offset)
+    }
+
+    fn set_prefix(&mut self, offset: usize, count: usize) -> Result<(), Error> {
+        let p = unsafe { self.lp.0.add(offset) };
+        match self.lp.replace_val(p, Value::Int(count as i64)) {
+            Some(_) => Ok(()),
+            None => Err(Error::Alloc)
+        }
+    }
+
+    fn compound(&mut self, prefixed: bool) -> Result<Compound<'_>, Error> {
+        let prefix = if prefixed { Some(self.push_prefix()?) } else { None };
+        Ok(Compound {
+            ser: self,
+            prefix,
+            count: 0,
+        })
+    }
+}
+
+impl Default for Serializer {
+    fn default() -> Self {
+        Serializer::new()
+    }
+}
+
+/// State of a sequence, map, struct or tuple being serialized.
+pub struct Compound<'a> {
+    ser: &'a mut Serializer,
+    prefix: Option<usize>,
+    count: usize,
+}
+
+impl<'a> Compound<'a> {
+    #[inline]
+    fn element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.count += 1;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        match self.prefix {
+            Some(offset) => self.ser.set_prefix(offset, self.count),
+            None => Ok(())
+        }
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        let bytes = v.to_le_bytes();
+        self.push((&bytes[..]).into())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        let bytes = v.to_le_bytes();
+        self.push((&bytes[..]).into())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.push(Value::Int(v as i64))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.push(v.into())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.push(Value::Int(0))
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+        self.push(Value::Int(1))?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), Error> {
+        self.push(variant_index.into())
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.push(variant_index.into())?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, Error> {
+        self.compound(true)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>, Error> {
+        self.compound(false)
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.compound(false)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.push(variant_index.into())?;
+        self.compound(false)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, Error> {
+        self.compound(true)
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.compound(true)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, Error> {
+        self.push(variant_index.into())?;
+        self.compound(true)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    /// Counts pairs rather than entries.
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+/// Bytes serialized with `serialize_bytes()` rather than as a sequence.
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Serialize for Bytes<'a> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Human readable formats get the integer or the string, or the bytes if it
+/// isn't UTF-8. Binary formats get an enum with `Int` and `String` variants.
+impl Serialize for Value {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Value::Int(v) if serializer.is_human_readable() => serializer.serialize_i64(v),
+            Value::Int(v) => serializer.serialize_newtype_variant("Value", 0, "Int", &v),
+            s if serializer.is_human_readable() => match std::str::from_utf8(s.as_bytes()) {
+                Ok(s) => serializer.serialize_str(s),
+                Err(_) => serializer.serialize_bytes(s.as_bytes())
+            },
+            s => serializer.serialize_newtype_variant("Value", 1, "String", &Bytes(s.as_bytes()))
+        }
+    }
+}
+
+impl Serialize for OwnedValue {
+    #[inline]
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_value().serialize(serializer)
+    }
+}
+
+/// Human readable formats get the sequence of values, binary formats the
+/// serialized listpack bytes.
+impl Serialize for Listpack {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let mut seq = serializer.serialize_seq(Some(length(self.0) as usize))?;
+            let mut ele = first(self.0);
+            while let Some(p) = ele {
+                seq.serialize_element(&get(p))?;
+                ele = next(self.0, p);
+            }
+            seq.end()
+        } else {
+            let bytes = unsafe {
+                std::slice::from_raw_parts(self.0, get_total_bytes(self.0) as usize)
+            };
+            serializer.serialize_bytes(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(lp: &Listpack) -> Vec<OwnedValue> {
+        let mut values = Vec::new();
+        let mut ele = first(lp.0);
+        while let Some(p) = ele {
+            values.push(get(p).to_owned_value());
+            ele = next(lp.0, p);
+        }
+        values
+    }
+
+    #[derive(Serialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+        label: Option<String>,
+    }
+
+    #[test]
+    fn flattened_entries() {
+        let lp = to_listpack(&Point { x: 1, y: -300, label: Some("a".to_string()) }).unwrap();
+        assert_eq!(entries(&lp), vec![
+            OwnedValue::Int(3),
+            OwnedValue::Int(1),
+            OwnedValue::Int(-300),
+            OwnedValue::Int(1),
+            OwnedValue::String(b"a".to_vec()),
+        ]);
+
+        // 1 byte 7 bit uint, 2 bytes 13 bit int, 3 bytes 16 bit int.
+        let lp = to_listpack(&(1u8, 300u16, 30000i32)).unwrap();
+        assert_eq!(get_total_bytes(lp.0), 6 + 2 + 3 + 4 + 1);
+
+        // The prefix grows once the count needs a larger encoding.
+        let seq: Vec<u32> = (0..200).collect();
+        let lp = to_listpack(&seq).unwrap();
+        assert_eq!(length(lp.0), 201);
+        assert_eq!(get(first(lp.0).unwrap()).as_int(), Some(200));
+        assert_eq!(get(last(lp.0).unwrap()).as_int(), Some(199));
+    }
+}
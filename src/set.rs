@@ -0,0 +1,247 @@
+use std;
+
+use ::raw::*;
+use ::Listpack;
+
+/// Source of randomness for `ListpackSet::random_member()` and
+/// `ListpackSet::pop()`. It is implemented for closures returning random
+/// 64 bit integers, so any RNG can be plugged in.
+pub trait RandomSource {
+    fn next_u64(&mut self) -> u64;
+}
+
+impl<F: FnMut() -> u64> RandomSource for F {
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        self()
+    }
+}
+
+/// Small set encoded the way Redis 7.2 encodes small sets: a listpack with
+/// one entry per member. Members that are the canonical representation of
+/// an integer are integer encoded and compared as integers, without
+/// decoding any string.
+pub struct ListpackSet {
+    lp: Listpack,
+}
+
+impl ListpackSet {
+    pub fn new() -> ListpackSet {
+        ListpackSet {
+            lp: Listpack::new(),
+        }
+    }
+
+    /// Number of members.
+    #[inline]
+    pub fn len(&self) -> usize {
+        length(self.lp.0) as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        first(self.lp.0).is_none()
+    }
+
+    /// Find the element holding the member 'v'.
+    fn position(&self, v: Value) -> Option<element> {
+        let mut p = first(self.lp.0)?;
+        match v {
+            Value::Int(v) => {
+                // Only integer encoded elements can match.
+                loop {
+                    let b = unsafe { *p };
+                    if !(is_6bit_str(b) || is_12bit_str(b) || is_32bit_str(b))
+                        && get_int(p) == v {
+                        return Some(p);
+                    }
+                    p = next(self.lp.0, p)?;
+                }
+            }
+            s => find(self.lp.0, p, s.as_bytes(), 0)
+        }
+    }
+
+    #[inline]
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.position(Value::compact(member)).is_some()
+    }
+
+    /// Add 'member' unless it is already in the set. Returns true if it was
+    /// added.
+    pub fn insert(&mut self, member: &[u8]) -> bool {
+        let v = Value::compact(member);
+        if self.position(v).is_some() {
+            false
+        } else {
+            self.lp.append_val(v)
+        }
+    }
+
+    /// Remove 'member'. Returns true if it was present.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.position(Value::compact(member)) {
+            Some(p) => self.lp.delete(p).is_some(),
+            None => false
+        }
+    }
+
+    /// Pick a member at random without removing it.
+    pub fn random_member<R: RandomSource>(&self, rng: &mut R) -> Option<Value> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let index = (rng.next_u64() % len as u64) as isize;
+        seek(self.lp.0, index).map(get)
+    }
+
+    /// Remove a member picked at random and return it.
+    pub fn pop<R: RandomSource>(&mut self, rng: &mut R) -> Option<OwnedValue> {
+        let len = self.len();
+        if len == 0 {
+            return None;
+        }
+        let index = (rng.next_u64() % len as u64) as isize;
+        let p = seek(self.lp.0, index)?;
+        let member = get(p).to_owned_value();
+        self.lp.delete(p);
+        Some(member)
+    }
+
+    /// Iterate the members in insertion order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = Value> + 'a {
+        let lp = self.lp.0;
+        let mut ele = first(lp);
+        std::iter::from_fn(move || {
+            let p = ele?;
+            ele = next(lp, p);
+            Some(get(p))
+        })
+    }
+
+    /// Build a set from members already known to be unique, or None if the
+    /// allocator failed.
+    fn from_unique<I: IntoIterator<Item = Value>>(members: I) -> Option<ListpackSet> {
+        let mut set = ListpackSet::new();
+        if set.lp.try_extend(members) {
+            Some(set)
+        } else {
+            None
+        }
+    }
+
+    /// Members that are in either set, or None if the allocator failed.
+    pub fn union(&self, other: &ListpackSet) -> Option<ListpackSet> {
+        ListpackSet::from_unique(
+            self.iter().chain(other.iter().filter(|&v| self.position(v).is_none()))
+        )
+    }
+
+    /// Members that are in both sets, or None if the allocator failed.
+    pub fn intersection(&self, other: &ListpackSet) -> Option<ListpackSet> {
+        ListpackSet::from_unique(self.iter().filter(|&v| other.position(v).is_some()))
+    }
+
+    /// Members of this set that are not in 'other', or None if the allocator
+    /// failed.
+    pub fn difference(&self, other: &ListpackSet) -> Option<ListpackSet> {
+        ListpackSet::from_unique(self.iter().filter(|&v| other.position(v).is_none()))
+    }
+
+    #[inline]
+    pub fn as_listpack(&self) -> &Listpack {
+        &self.lp
+    }
+
+    #[inline]
+    pub fn into_listpack(self) -> Listpack {
+        self.lp
+    }
+}
+
+impl Default for ListpackSet {
+    fn default() -> Self {
+        ListpackSet::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: &[&str]) -> ListpackSet {
+        let mut set = ListpackSet::new();
+        for m in members {
+            set.insert(m.as_bytes());
+        }
+        set
+    }
+
+    fn sorted(set: &ListpackSet) -> Vec<OwnedValue> {
+        let mut members: Vec<OwnedValue> = set.iter()
+            .map(|v| v.to_owned_value())
+            .collect();
+        members.sort();
+        members
+    }
+
+    fn owned(members: &[&str]) -> Vec<OwnedValue> {
+        let mut members: Vec<OwnedValue> = members.iter()
+            .map(|m| Value::compact(m.as_bytes()).to_owned_value())
+            .collect();
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn insert_remove() {
+        let mut s = set(&["a", "1", "b"]);
+        assert!(!s.insert(b"a"));
+        assert!(!s.insert(b"1"));
+        assert!(s.insert(b"01"));
+        assert_eq!(s.len(), 4);
+
+        assert!(s.contains(b"1"));
+        assert!(s.contains(b"01"));
+        assert!(!s.contains(b"2"));
+
+        // "1" is integer encoded.
+        let p = s.position(Value::Int(1)).unwrap();
+        assert!(is_7bit_uint(unsafe { *p }));
+
+        assert!(s.remove(b"1"));
+        assert!(!s.remove(b"1"));
+        assert_eq!(sorted(&s), owned(&["a", "b", "01"]));
+    }
+
+    #[test]
+    fn random() {
+        let mut s = set(&["a", "b", "c"]);
+        let mut counter = 0u64;
+        let mut rng = || {
+            counter += 1;
+            counter
+        };
+
+        assert!(s.random_member(&mut rng).unwrap() == "b".into());
+        assert!(s.pop(&mut rng).unwrap() == OwnedValue::String(b"c".to_vec()));
+        assert_eq!(s.len(), 2);
+        assert!(s.pop(&mut rng).is_some());
+        assert!(s.pop(&mut rng).is_some());
+        assert!(s.pop(&mut rng).is_none());
+        assert!(s.random_member(&mut rng).is_none());
+    }
+
+    #[test]
+    fn algebra() {
+        let a = set(&["1", "2", "3", "x"]);
+        let b = set(&["3", "4", "x", "y"]);
+
+        assert_eq!(sorted(&a.union(&b).unwrap()), owned(&["1", "2", "3", "4", "x", "y"]));
+        assert_eq!(sorted(&a.intersection(&b).unwrap()), owned(&["3", "x"]));
+        assert_eq!(sorted(&a.difference(&b).unwrap()), owned(&["1", "2"]));
+        assert_eq!(a.union(&b).unwrap().len(), 6);
+        assert!(a.intersection(&ListpackSet::new()).unwrap().is_empty());
+    }
+}
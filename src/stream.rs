@@ -0,0 +1,448 @@
+use ::raw::*;
+use ::Listpack;
+
+/// Entry flag of a regular entry.
+pub const FLAG_NONE: i64 = 0;
+/// Entry flag of an entry that was deleted but not yet compacted away.
+pub const FLAG_DELETED: i64 = 1 << 0;
+/// Entry flag of an entry with the same fields as the master entry, in
+/// which case only the values are stored.
+pub const FLAG_SAME_FIELDS: i64 = 1 << 1;
+
+/// ID of a stream entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    #[inline]
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    /// Decode the 128 bit big endian radix tree key under which Redis
+    /// stores the node with this master ID.
+    pub fn from_key(key: &[u8]) -> Option<StreamId> {
+        if key.len() != 16 {
+            return None;
+        }
+        let mut ms = [0u8; 8];
+        let mut seq = [0u8; 8];
+        ms.copy_from_slice(&key[..8]);
+        seq.copy_from_slice(&key[8..]);
+        Some(StreamId::new(u64::from_be_bytes(ms), u64::from_be_bytes(seq)))
+    }
+
+    /// Encode the ID as a radix tree key.
+    pub fn to_key(&self) -> [u8; 16] {
+        let mut key = [0u8; 16];
+        key[..8].copy_from_slice(&self.ms.to_be_bytes());
+        key[8..].copy_from_slice(&self.seq.to_be_bytes());
+        key
+    }
+}
+
+/// An entry read from a node: its flags, ID and field/value pairs.
+struct RawEntry {
+    flags_ele: element,
+    flags: i64,
+    id: StreamId,
+    fields: Vec<(Value, Value)>,
+    next: Option<element>,
+}
+
+/// Walks the elements of a listpack, decoding them.
+struct Cursor {
+    lp: listpack,
+    p: Option<element>,
+}
+
+impl Cursor {
+    #[inline]
+    fn value(&mut self) -> Option<Value> {
+        let p = self.p?;
+        self.p = next(self.lp, p);
+        Some(get(p))
+    }
+
+    #[inline]
+    fn int(&mut self) -> Option<i64> {
+        self.value()?.as_int()
+    }
+}
+
+/// A node of a Redis stream: a listpack holding a master entry followed by
+/// entries whose IDs are stored as deltas from the master ID, which is the
+/// radix tree key of the node rather than part of the listpack.
+///
+/// The master entry is laid out as
+///
+/// ```text
+/// count | deleted | num-fields | field_1 | ... | field_N | 0
+/// ```
+///
+/// and every entry as
+///
+/// ```text
+/// flags | ms-diff | seq-diff | num-fields | field_1 | value_1 | ... | lp-count
+/// ```
+///
+/// where entries flagged `FLAG_SAME_FIELDS` only store the values, and
+/// `lp-count` is the number of elements of the entry before it, so the node
+/// can also be walked backwards.
+pub struct StreamNode {
+    lp: Listpack,
+    master: StreamId,
+}
+
+impl StreamNode {
+    /// Create a node with the master ID and master fields, which are usually
+    /// those of the first entry appended.
+    pub fn new(master: StreamId, fields: &[&[u8]]) -> StreamNode {
+        let mut lp = Listpack::new();
+        let mut values = Vec::with_capacity(fields.len() + 4);
+        values.push(Value::Int(0));
+        values.push(Value::Int(0));
+        values.push(Value::Int(fields.len() as i64));
+        values.extend(fields.iter().map(|f| Value::compact(f)));
+        values.push(Value::Int(0));
+        lp.extend(values);
+        StreamNode { lp, master }
+    }
+
+    /// Wrap a listpack holding a stream node, for instance one read from an
+    /// RDB dump. Returns None if the master entry is malformed.
+    pub fn from_listpack(master: StreamId, lp: Listpack) -> Option<StreamNode> {
+        let node = StreamNode { lp, master };
+        node.master_entry()?;
+        Some(node)
+    }
+
+    #[inline]
+    pub fn master_id(&self) -> StreamId {
+        self.master
+    }
+
+    /// Number of entries that are not deleted.
+    #[inline]
+    pub fn len(&self) -> usize {
+        first(self.lp.0)
+            .and_then(|p| get(p).as_int())
+            .unwrap_or(0) as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of entries flagged as deleted.
+    #[inline]
+    pub fn deleted(&self) -> usize {
+        first(self.lp.0)
+            .and_then(|p| next(self.lp.0, p))
+            .and_then(|p| get(p).as_int())
+            .unwrap_or(0) as usize
+    }
+
+    pub fn master_fields(&self) -> Vec<Value> {
+        self.master_entry()
+            .map(|(fields, _)| fields)
+            .unwrap_or_default()
+    }
+
+    /// Decode the master entry, returning the master fields and the element
+    /// following its terminator.
+    fn master_entry(&self) -> Option<(Vec<Value>, Option<element>)> {
+        let mut cursor = Cursor { lp: self.lp.0, p: first(self.lp.0) };
+        cursor.int()?;
+        cursor.int()?;
+        let count = cursor.int()?;
+        if count < 0 {
+            return None;
+        }
+        let mut fields = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            fields.push(cursor.value()?);
+        }
+        if cursor.int()? != 0 {
+            return None;
+        }
+        Some((fields, cursor.p))
+    }
+
+    /// Decode the entry starting at 'p'.
+    fn read_entry(&self, master_fields: &[Value], p: element) -> Option<RawEntry> {
+        let mut cursor = Cursor { lp: self.lp.0, p: Some(p) };
+        let flags = cursor.int()?;
+        let ms = cursor.int()?;
+        let seq = cursor.int()?;
+
+        let fields = if flags & FLAG_SAME_FIELDS != 0 {
+            let mut fields = Vec::with_capacity(master_fields.len());
+            for &field in master_fields {
+                fields.push((field, cursor.value()?));
+            }
+            fields
+        } else {
+            let count = cursor.int()?;
+            if count < 0 {
+                return None;
+            }
+            let mut fields = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let field = cursor.value()?;
+                fields.push((field, cursor.value()?));
+            }
+            fields
+        };
+        // lp-count
+        cursor.int()?;
+
+        Some(RawEntry {
+            flags_ele: p,
+            flags,
+            id: StreamId::new(
+                self.master.ms.wrapping_add(ms as u64),
+                self.master.seq.wrapping_add(seq as u64),
+            ),
+            fields,
+            next: cursor.p,
+        })
+    }
+
+    /// Append an entry. Fields matching the master fields, in the same order,
+    /// are stored compressed like Redis does. Returns false, leaving the node
+    /// untouched, if 'id' is lower than the master ID or the allocator
+    /// failed.
+    pub fn append(&mut self, id: StreamId, fields: &[(&[u8], &[u8])]) -> bool {
+        if id < self.master {
+            return false;
+        }
+        let master_fields = match self.master_entry() {
+            Some((master_fields, _)) => master_fields,
+            None => return false
+        };
+
+        let same_fields = master_fields.len() == fields.len()
+            && master_fields.iter()
+                .zip(fields.iter())
+                .all(|(m, &(f, _))| *m == Value::compact(f));
+
+        let mut values = Vec::with_capacity(fields.len() * 2 + 5);
+        values.push(Value::Int(if same_fields { FLAG_SAME_FIELDS } else { FLAG_NONE }));
+        values.push(Value::Int(id.ms.wrapping_sub(self.master.ms) as i64));
+        values.push(Value::Int(id.seq.wrapping_sub(self.master.seq) as i64));
+        let mut lp_count = fields.len() as i64 + 3;
+        if same_fields {
+            values.extend(fields.iter().map(|&(_, v)| Value::compact(v)));
+        } else {
+            values.push(Value::Int(fields.len() as i64));
+            for &(f, v) in fields {
+                values.push(Value::compact(f));
+                values.push(Value::compact(v));
+            }
+            lp_count += fields.len() as i64 + 1;
+        }
+        values.push(Value::Int(lp_count));
+
+        let added = values.len();
+        if !self.lp.try_extend(values) {
+            return false;
+        }
+        let count = self.len() as i64;
+        if !self.set_header(0, count + 1) {
+            // Deleting never needs to allocate.
+            self.lp.delete_range(-(added as isize), added as u32);
+            return false;
+        }
+        true
+    }
+
+    /// Flag the entry with 'id' as deleted. Returns true if a live entry
+    /// with that ID was found, or None, leaving the node untouched, if the
+    /// allocator failed.
+    pub fn remove(&mut self, id: StreamId) -> Option<bool> {
+        let (master_fields, mut ele) = match self.master_entry() {
+            Some(master) => master,
+            None => return Some(false)
+        };
+
+        while let Some(p) = ele {
+            let entry = match self.read_entry(&master_fields, p) {
+                Some(entry) => entry,
+                None => return Some(false)
+            };
+            if entry.id == id && entry.flags & FLAG_DELETED == 0 {
+                // The flags keep their size and the live count only shrinks,
+                // so only the deleted count may need to grow the listpack.
+                let flags_ele = self.lp.replace_val(entry.flags_ele, Value::Int(entry.flags | FLAG_DELETED))?;
+                let (count, deleted) = (self.len() as i64, self.deleted() as i64);
+                if !self.set_header(1, deleted + 1) {
+                    self.lp.replace_val(flags_ele, Value::Int(entry.flags));
+                    return None;
+                }
+                if !self.set_header(0, count - 1) {
+                    return None;
+                }
+                return Some(true);
+            }
+            ele = entry.next;
+        }
+        Some(false)
+    }
+
+    /// Replace the integer at 'index' of the master entry. Returns false if
+    /// the allocator failed.
+    fn set_header(&mut self, index: isize, v: i64) -> bool {
+        match seek(self.lp.0, index) {
+            Some(p) => self.lp.replace_val(p, Value::Int(v)).is_some(),
+            None => false
+        }
+    }
+
+    /// Iterate the entries that are not deleted as `(ms, seq, fields)`, in
+    /// ID order. Iteration stops at the first malformed entry.
+    pub fn iter(&self) -> Iter<'_> {
+        let (master_fields, ele) = self.master_entry().unwrap_or((Vec::new(), None));
+        Iter {
+            node: self,
+            master_fields,
+            ele,
+        }
+    }
+
+    #[inline]
+    pub fn as_listpack(&self) -> &Listpack {
+        &self.lp
+    }
+
+    #[inline]
+    pub fn into_listpack(self) -> Listpack {
+        self.lp
+    }
+}
+
+/// Iterator over the live entries of a `StreamNode`.
+pub struct Iter<'a> {
+    node: &'a StreamNode,
+    master_fields: Vec<Value>,
+    ele: Option<element>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (u64, u64, Vec<(Value, Value)>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.node.read_entry(&self.master_fields, self.ele?);
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    self.ele = None;
+                    return None;
+                }
+            };
+            self.ele = entry.next;
+            if entry.flags & FLAG_DELETED == 0 {
+                return Some((entry.id.ms, entry.id.seq, entry.fields));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(lp: &Listpack) -> Vec<Option<i64>> {
+        let mut values = Vec::new();
+        let mut ele = first(lp.0);
+        while let Some(p) = ele {
+            values.push(get(p).as_int());
+            ele = next(lp.0, p);
+        }
+        values
+    }
+
+    #[test]
+    fn layout() {
+        let master = StreamId::new(1000, 5);
+        let mut node = StreamNode::new(master, &[b"name", b"age"]);
+        assert!(node.append(master, &[(b"name", b"a"), (b"age", b"10")]));
+        assert!(node.append(StreamId::new(1001, 0), &[(b"city", b"x")]));
+        assert!(!node.append(StreamId::new(999, 0), &[]));
+
+        // count, deleted, num-fields, name, age, 0
+        // SAME_FIELDS, 0, 0, "a", 10, lp-count 5
+        // NONE, 1, -5, 1, city, x, lp-count 6
+        assert_eq!(ints(node.as_listpack()), vec![
+            Some(2), Some(0), Some(2), None, None, Some(0),
+            Some(2), Some(0), Some(0), None, Some(10), Some(5),
+            Some(0), Some(1), Some(-5), Some(1), None, None, Some(6),
+        ]);
+        assert_eq!(node.len(), 2);
+        assert!(node.master_fields()[0] == "name".into());
+    }
+
+    #[test]
+    fn wide_header_counts() {
+        // The counts outgrow a 7 bit integer, so updating them moves the
+        // entries that follow.
+        let master = StreamId::new(1, 0);
+        let mut node = StreamNode::new(master, &[b"f"]);
+        for i in 0..200u64 {
+            assert!(node.append(StreamId::new(1, i), &[(b"f", b"v")]));
+        }
+        for i in 0..150u64 {
+            assert_eq!(node.remove(StreamId::new(1, i)), Some(true));
+        }
+        assert_eq!(node.len(), 50);
+        assert_eq!(node.deleted(), 150);
+        assert_eq!(node.iter().next().map(|(ms, seq, _)| (ms, seq)), Some((1, 150)));
+    }
+
+    #[test]
+    fn iterate_and_remove() {
+        let master = StreamId::new(1, 0);
+        let mut node = StreamNode::new(master, &[b"f"]);
+        for i in 0..5u64 {
+            let v = i.to_string();
+            node.append(StreamId::new(1 + i / 2, i % 2), &[(b"f", v.as_bytes())]);
+        }
+        node.append(StreamId::new(9, 9), &[(b"g", b"x"), (b"h", b"y")]);
+
+        let entries: Vec<(u64, u64, Vec<(Value, Value)>)> = node.iter().collect();
+        assert_eq!(entries.len(), 6);
+        assert_eq!((entries[3].0, entries[3].1), (2, 1));
+        assert!(entries[3].2[0].0 == "f".into());
+        assert_eq!(entries[3].2[0].1.as_int(), Some(3));
+        assert_eq!(entries[5].2.len(), 2);
+        assert!(entries[5].2[1].1 == "y".into());
+
+        assert_eq!(node.remove(StreamId::new(2, 1)), Some(true));
+        assert_eq!(node.remove(StreamId::new(2, 1)), Some(false));
+        assert_eq!(node.remove(StreamId::new(7, 0)), Some(false));
+        assert_eq!(node.len(), 5);
+        assert_eq!(node.deleted(), 1);
+        let ids: Vec<(u64, u64)> = node.iter().map(|(ms, seq, _)| (ms, seq)).collect();
+        assert_eq!(ids, vec![(1, 0), (1, 1), (2, 0), (3, 0), (9, 9)]);
+
+        // The layout survives a round trip through a bare listpack.
+        let node = StreamNode::from_listpack(master, node.into_listpack()).unwrap();
+        assert_eq!(node.iter().count(), 5);
+        assert!(StreamNode::from_listpack(master, Listpack::new()).is_none());
+    }
+
+    #[test]
+    fn keys() {
+        let id = StreamId::new(0x0102030405060708, 9);
+        let key = id.to_key();
+        assert_eq!(key[0], 1);
+        assert_eq!(key[15], 9);
+        assert_eq!(StreamId::from_key(&key), Some(id));
+        assert_eq!(StreamId::from_key(&key[1..]), None);
+    }
+}
@@ -0,0 +1,223 @@
+use std;
+use std::cell::Cell;
+
+use raw::*;
+
+/// Allocator wrapper that keeps count of the memory used by the listpacks
+/// allocated through it, like the Redis `used_memory` accounting, and can
+/// enforce a budget like `maxmemory`. An allocation or a reallocation that
+/// would grow the live bytes past the budget fails, so the mutation that
+/// needed it returns `None` (`false` from `Listpack`) and leaves the
+/// listpack untouched. Shrinking is always allowed.
+///
+/// Sizes are those requested for the listpacks, which may be less than what
+/// the wrapped allocator reserves. Share the tracker between listpacks by
+/// reference, e.g. `Listpack::new_in(&tracker)`.
+pub struct TrackingAllocator<A: Allocator> {
+    inner: A,
+    budget: Cell<Option<usize>>,
+    live: Cell<usize>,
+    peak: Cell<usize>,
+    allocs: Cell<usize>,
+    reallocs: Cell<usize>,
+    frees: Cell<usize>,
+    rejected: Cell<usize>,
+}
+
+impl<A: Allocator> TrackingAllocator<A> {
+    pub fn new(inner: A) -> TrackingAllocator<A> {
+        TrackingAllocator {
+            inner,
+            budget: Cell::new(None),
+            live: Cell::new(0),
+            peak: Cell::new(0),
+            allocs: Cell::new(0),
+            reallocs: Cell::new(0),
+            frees: Cell::new(0),
+            rejected: Cell::new(0),
+        }
+    }
+
+    /// Create a tracker that refuses to grow past 'budget' live bytes.
+    pub fn with_budget(inner: A, budget: usize) -> TrackingAllocator<A> {
+        let tracker = TrackingAllocator::new(inner);
+        tracker.set_budget(Some(budget));
+        tracker
+    }
+
+    #[inline]
+    pub fn budget(&self) -> Option<usize> {
+        self.budget.get()
+    }
+
+    /// Change the budget. Lowering it below the live bytes doesn't free
+    /// anything, but every further growth is refused until enough is freed.
+    #[inline]
+    pub fn set_budget(&self, budget: Option<usize>) {
+        self.budget.set(budget)
+    }
+
+    /// Bytes used by the live listpacks.
+    #[inline]
+    pub fn live(&self) -> usize {
+        self.live.get()
+    }
+
+    /// Highest value of `live()` so far.
+    #[inline]
+    pub fn peak(&self) -> usize {
+        self.peak.get()
+    }
+
+    /// Number of successful calls to `alloc`.
+    #[inline]
+    pub fn allocs(&self) -> usize {
+        self.allocs.get()
+    }
+
+    /// Number of successful calls to `realloc`.
+    #[inline]
+    pub fn reallocs(&self) -> usize {
+        self.reallocs.get()
+    }
+
+    /// Number of calls to `dealloc`.
+    #[inline]
+    pub fn frees(&self) -> usize {
+        self.frees.get()
+    }
+
+    /// Number of allocations and reallocations refused by the budget.
+    #[inline]
+    pub fn rejected(&self) -> usize {
+        self.rejected.get()
+    }
+
+    #[inline]
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Return true if 'additional' more live bytes fit in the budget.
+    #[inline]
+    fn admits(&self, additional: usize) -> bool {
+        match self.budget.get() {
+            Some(budget) if self.live.get() + additional > budget => {
+                self.rejected.set(self.rejected.get() + 1);
+                false
+            }
+            _ => true
+        }
+    }
+
+    #[inline]
+    fn set_live(&self, live: usize) {
+        self.live.set(live);
+        if live > self.peak.get() {
+            self.peak.set(live);
+        }
+    }
+}
+
+impl<A: Allocator> Allocator for TrackingAllocator<A> {
+    #[inline(always)]
+    fn has_header(&self) -> bool {
+        self.inner.has_header()
+    }
+
+    fn alloc(&self, size: usize) -> listpack {
+        if !self.admits(size) {
+            return std::ptr::null_mut();
+        }
+        let lp = self.inner.alloc(size);
+        if !lp.is_null() {
+            self.allocs.set(self.allocs.get() + 1);
+            self.set_live(self.live.get() + size);
+        }
+        lp
+    }
+
+    fn realloc(&self, lp: listpack, old_size: usize, new_size: usize) -> listpack {
+        if new_size > old_size && !self.admits(new_size - old_size) {
+            return std::ptr::null_mut();
+        }
+        let new_lp = self.inner.realloc(lp, old_size, new_size);
+        if !new_lp.is_null() {
+            self.reallocs.set(self.reallocs.get() + 1);
+            self.set_live(self.live.get() + new_size - old_size);
+        }
+        new_lp
+    }
+
+    fn dealloc(&self, lp: listpack, size: usize) {
+        self.inner.dealloc(lp, size);
+        self.frees.set(self.frees.get() + 1);
+        self.live.set(self.live.get().saturating_sub(size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::Listpack;
+
+    #[test]
+    fn counts() {
+        let tracker = TrackingAllocator::new(DefaultAllocator);
+        {
+            let mut a = Listpack::new_in(&tracker);
+            let mut b = Listpack::new_in(&tracker);
+            assert_eq!(tracker.live(), 2 * 7);
+
+            a.extend(0..10);
+            b.append("hello");
+            assert_eq!(tracker.live(), a.as_bytes().len() + b.as_bytes().len());
+
+            a.delete_range(0, 5);
+            assert_eq!(tracker.live(), a.as_bytes().len() + b.as_bytes().len());
+            assert_eq!(tracker.peak(), 7 + 10 * 2 + b.as_bytes().len());
+        }
+        assert_eq!(tracker.live(), 0);
+        assert_eq!(tracker.allocs(), 2);
+        assert_eq!(tracker.reallocs(), 3);
+        assert_eq!(tracker.frees(), 2);
+    }
+
+    #[test]
+    fn budget() {
+        let tracker = TrackingAllocator::with_budget(DefaultAllocator, 7 + 5 * 2);
+        let mut lp = Listpack::new_in(&tracker);
+        for i in 0..5 {
+            assert!(lp.append(i));
+        }
+        let before = lp.as_bytes().to_vec();
+        assert!(!lp.append(5));
+        assert!(!lp.append("too long"));
+        assert_eq!(lp.as_bytes(), &before[..]);
+        assert!(append(&tracker, lp.0, Value::Int(5)).is_none());
+        assert!(insert(&tracker, lp.0, Value::Int(5), Placement::Before, first(lp.0).unwrap()).is_none());
+        assert!(!lp.try_extend(vec![5, 6]));
+        assert_eq!(lp.as_bytes(), &before[..]);
+        assert_eq!(tracker.rejected(), 5);
+
+        // Another listpack doesn't fit either.
+        assert!(tracker.alloc(7).is_null());
+
+        // Freeing makes room again.
+        lp.delete_range(0, 1);
+        assert!(lp.append(5));
+        assert_eq!(tracker.live(), 7 + 5 * 2);
+
+        tracker.set_budget(None);
+        assert!(lp.append("no limit"));
+        assert_eq!(tracker.peak(), lp.as_bytes().len());
+    }
+
+    #[test]
+    #[should_panic(expected = "listpack allocation failed")]
+    fn extend_over_budget() {
+        let tracker = TrackingAllocator::with_budget(DefaultAllocator, 7 + 5 * 2);
+        let mut lp = Listpack::new_in(&tracker);
+        lp.extend(0..10);
+    }
+}
@@ -0,0 +1,459 @@
+use std;
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+use ::raw::*;
+use ::Listpack;
+
+/// Small sorted set encoded the way Redis encodes small zsets: a listpack of
+/// member/score pairs ordered by score, and by member for equal scores.
+/// Scores are stored as their shortest decimal representation, which is
+/// integer encoded for integral scores, so blobs can be exchanged with Redis.
+pub struct ListpackSortedSet<A: Allocator = CapacityAllocator> {
+    lp: Listpack<A>,
+}
+
+/// Format a score the way Redis `d2string()` does before storing it.
+fn score_to_string(score: f64) -> String {
+    if score.is_nan() {
+        "nan".to_string()
+    } else if score.is_infinite() {
+        if score < 0.0 { "-inf".to_string() } else { "inf".to_string() }
+    } else if score == 0.0 {
+        if score.is_sign_negative() { "-0".to_string() } else { "0".to_string() }
+    } else {
+        // Same range check as Redis `double2ll()`, integers are printed as
+        // such so they end up integer encoded.
+        let limit = (i64::MAX / 2) as f64;
+        if (-limit..=limit).contains(&score) && (score as i64) as f64 == score {
+            (score as i64).to_string()
+        } else {
+            dtoa(score)
+        }
+    }
+}
+
+/// Shortest round-tripping representation of 'score', laid out the way
+/// Redis `fpconv_dtoa()` does: like "%.17g" but without the noise digits.
+fn dtoa(score: f64) -> String {
+    // "{:e}" gives the shortest digits, e.g. "-1.2345e-5".
+    let sci = format!("{:e}", score.abs());
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+
+    let mut out = String::with_capacity(24);
+    if score < 0.0 {
+        out.push('-');
+    }
+    if !(-4..17).contains(&exp) {
+        out.push_str(&digits[..1]);
+        if digits.len() > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        out.push_str(&format!("e{}{:02}", if exp < 0 { '-' } else { '+' }, exp.abs()));
+    } else if exp < 0 {
+        out.push_str("0.");
+        for _ in 0..(-exp - 1) {
+            out.push('0');
+        }
+        out.push_str(&digits);
+    } else {
+        let int_len = exp as usize + 1;
+        if digits.len() > int_len {
+            out.push_str(&digits[..int_len]);
+            out.push('.');
+            out.push_str(&digits[int_len..]);
+        } else {
+            out.push_str(&digits);
+            for _ in digits.len()..int_len {
+                out.push('0');
+            }
+        }
+    }
+    out
+}
+
+/// Decode the score stored in the element pointed by 'p'.
+fn score_of(p: element) -> f64 {
+    match get(p) {
+        Value::Int(v) => v as f64,
+        s => std::str::from_utf8(s.as_bytes())
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0.0)
+    }
+}
+
+/// Compare the member pointed by 'p' with 's' as strings, rendering integer
+/// encoded members in decimal, like `zzlCompareElements()`.
+fn member_cmp(p: element, s: &[u8]) -> Ordering {
+    match get(p) {
+        Value::Int(v) => v.to_string().as_bytes().cmp(s),
+        m => m.as_bytes().cmp(s)
+    }
+}
+
+#[inline]
+fn above_min(score: f64, min: Bound<f64>) -> bool {
+    match min {
+        Bound::Included(min) => score >= min,
+        Bound::Excluded(min) => score > min,
+        Bound::Unbounded => true
+    }
+}
+
+#[inline]
+fn below_max(score: f64, max: Bound<f64>) -> bool {
+    match max {
+        Bound::Included(max) => score <= max,
+        Bound::Excluded(max) => score < max,
+        Bound::Unbounded => true
+    }
+}
+
+impl ListpackSortedSet {
+    pub fn new() -> ListpackSortedSet {
+        ListpackSortedSet {
+            lp: Listpack::new(),
+        }
+    }
+}
+
+impl<A: Allocator> ListpackSortedSet<A> {
+    pub fn new_in(allocator: A) -> ListpackSortedSet<A> {
+        ListpackSortedSet {
+            lp: Listpack::new_in(allocator),
+        }
+    }
+
+    /// Number of members.
+    #[inline]
+    pub fn len(&self) -> usize {
+        length(self.lp.0) as usize / 2
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        first(self.lp.0).is_none()
+    }
+
+    /// Add 'member' with 'score', or update its score moving it to its new
+    /// position. Returns whether the member was added, or None, leaving the
+    /// set untouched, if the allocator failed. NaN scores are rejected like
+    /// Redis does.
+    pub fn add(&mut self, member: &[u8], score: f64) -> Option<bool> {
+        if score.is_nan() {
+            return Some(false);
+        }
+
+        match self.lp.find(member, 1) {
+            Some(p) => {
+                let current = score_of(next(self.lp.0, p)?);
+                if current != score {
+                    // The new pair goes in first so a failure loses nothing.
+                    if !self.insert(member, score) {
+                        return None;
+                    }
+                    let (old, _) = self.iter_elements()
+                        .find(|&(m, s)| compare(m, member) && score_of(s) == current)?;
+                    self.lp.delete_range_with_entry(old, 2);
+                }
+                Some(false)
+            }
+            None => {
+                if !self.insert(member, score) {
+                    return None;
+                }
+                Some(true)
+            }
+        }
+    }
+
+    /// Insert the pair before the first member that sorts after it. Returns
+    /// false if the allocator failed.
+    fn insert(&mut self, member: &[u8], score: f64) -> bool {
+        let mut target = std::ptr::null_mut();
+        let mut ele = first(self.lp.0);
+        while let Some(p) = ele {
+            let s = match next(self.lp.0, p) {
+                Some(s) => s,
+                None => break
+            };
+            let current = score_of(s);
+            if current > score || (current == score && member_cmp(p, member) == Ordering::Greater) {
+                target = p;
+                break;
+            }
+            ele = next(self.lp.0, s);
+        }
+
+        let score = score_to_string(score);
+        self.lp.insert_many(
+            vec![Value::compact(member), Value::compact(score.as_bytes())],
+            Placement::Before,
+            target,
+        ).is_some()
+    }
+
+    /// Remove 'member'. Returns true if it was present.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.lp.find(member, 1) {
+            Some(p) => self.lp.delete_range_with_entry(p, 2).is_some(),
+            None => false
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        let p = self.lp.find(member, 1)?;
+        next(self.lp.0, p).map(score_of)
+    }
+
+    /// Zero-based position of 'member' in ascending score order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        self.iter_elements()
+            .position(|(m, _)| compare(m, member))
+    }
+
+    /// Zero-based position of 'member' in descending score order.
+    pub fn rev_rank(&self, member: &[u8]) -> Option<usize> {
+        self.rank(member).map(|rank| self.len() - 1 - rank)
+    }
+
+    /// Members between the 'start' and 'stop' ranks, both inclusive.
+    /// Negative ranks count from the highest score, like `ZRANGE`.
+    pub fn range_by_rank(&self, start: isize, stop: isize) -> Vec<(Value, f64)> {
+        let len = self.len() as isize;
+        let start = if start < 0 { std::cmp::max(start + len, 0) } else { start };
+        let stop = if stop < 0 { stop + len } else { std::cmp::min(stop, len - 1) };
+        if start > stop || start >= len {
+            return Vec::new();
+        }
+
+        let mut ele = seek(self.lp.0, start * 2);
+        let mut range = Vec::with_capacity((stop - start + 1) as usize);
+        while let Some(p) = ele {
+            if range.len() as isize > stop - start {
+                break;
+            }
+            let s = match next(self.lp.0, p) {
+                Some(s) => s,
+                None => break
+            };
+            range.push((get(p), score_of(s)));
+            ele = next(self.lp.0, s);
+        }
+        range
+    }
+
+    /// Members with a score within the bounds, in ascending score order.
+    pub fn range_by_score(&self, min: Bound<f64>, max: Bound<f64>) -> Vec<(Value, f64)> {
+        self.iter()
+            .skip_while(|&(_, score)| !above_min(score, min))
+            .take_while(|&(_, score)| below_max(score, max))
+            .collect()
+    }
+
+    /// Members within the lexicographical bounds, like `ZRANGEBYLEX`. As in
+    /// Redis the result is only meaningful when all the members share the
+    /// same score.
+    pub fn range_by_lex(&self, min: Bound<&[u8]>, max: Bound<&[u8]>) -> Vec<(Value, f64)> {
+        self.iter_elements()
+            .skip_while(|&(m, _)| match min {
+                Bound::Included(min) => member_cmp(m, min) == Ordering::Less,
+                Bound::Excluded(min) => member_cmp(m, min) != Ordering::Greater,
+                Bound::Unbounded => false
+            })
+            .take_while(|&(m, _)| match max {
+                Bound::Included(max) => member_cmp(m, max) != Ordering::Greater,
+                Bound::Excluded(max) => member_cmp(m, max) == Ordering::Less,
+                Bound::Unbounded => true
+            })
+            .map(|(m, s)| (get(m), score_of(s)))
+            .collect()
+    }
+
+    /// Iterate the member/score pairs in ascending score order.
+    #[inline]
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Value, f64)> + 'a {
+        self.iter_elements().map(|(m, s)| (get(m), score_of(s)))
+    }
+
+    fn iter_elements<'a>(&'a self) -> impl Iterator<Item = (element, element)> + 'a {
+        let lp = self.lp.0;
+        let mut ele = first(lp);
+        std::iter::from_fn(move || {
+            let member = ele?;
+            let score = next(lp, member)?;
+            ele = next(lp, score);
+            Some((member, score))
+        })
+    }
+
+    #[inline]
+    pub fn as_listpack(&self) -> &Listpack<A> {
+        &self.lp
+    }
+
+    #[inline]
+    pub fn into_listpack(self) -> Listpack<A> {
+        self.lp
+    }
+}
+
+impl Default for ListpackSortedSet {
+    fn default() -> Self {
+        ListpackSortedSet::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracking::TrackingAllocator;
+
+    fn members(range: Vec<(Value, f64)>) -> Vec<String> {
+        range.into_iter()
+            .map(|(m, _)| match m {
+                Value::Int(v) => v.to_string(),
+                m => m.as_str().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn add_and_rank() {
+        let mut zs = ListpackSortedSet::new();
+        assert_eq!(zs.add(b"c", 3.0), Some(true));
+        assert_eq!(zs.add(b"a", 1.0), Some(true));
+        assert_eq!(zs.add(b"b", 2.5), Some(true));
+        assert_eq!(zs.add(b"10", 2.5), Some(true));
+        assert_eq!(zs.add(b"a", 1.0), Some(false));
+        assert_eq!(zs.len(), 4);
+
+        // Equal scores are ordered by member.
+        assert_eq!(members(zs.range_by_rank(0, -1)), vec!["a", "10", "b", "c"]);
+        assert_eq!(zs.rank(b"b"), Some(2));
+        assert_eq!(zs.rev_rank(b"b"), Some(1));
+        assert_eq!(zs.score(b"b"), Some(2.5));
+        assert_eq!(zs.rank(b"z"), None);
+
+        // Updating the score moves the member.
+        assert_eq!(zs.add(b"a", 10.0), Some(false));
+        assert_eq!(members(zs.range_by_rank(0, -1)), vec!["10", "b", "c", "a"]);
+        assert_eq!(zs.score(b"a"), Some(10.0));
+        assert_eq!(zs.add(b"a", 0.5), Some(false));
+        assert_eq!(members(zs.range_by_rank(0, -1)), vec!["a", "10", "b", "c"]);
+        assert_eq!(zs.add(b"a", 10.0), Some(false));
+        assert_eq!(zs.add(b"a", std::f64::NAN), Some(false));
+        assert_eq!(zs.len(), 4);
+
+        assert!(zs.remove(b"10"));
+        assert!(!zs.remove(b"10"));
+        assert_eq!(members(zs.range_by_rank(-2, -1)), vec!["c", "a"]);
+        assert_eq!(members(zs.range_by_rank(1, 100)), vec!["c", "a"]);
+        assert!(zs.range_by_rank(3, 5).is_empty());
+    }
+
+    #[test]
+    fn add_failure() {
+        let tracker = TrackingAllocator::with_budget(DefaultAllocator, 7 + 5 * 2);
+        let mut zs = ListpackSortedSet::new_in(&tracker);
+        assert_eq!(zs.add(b"a", 1.0), Some(true));
+        assert_eq!(zs.add(b"b", 2.0), Some(true));
+        let before = zs.as_listpack().as_bytes().to_vec();
+
+        // Neither a new member nor a moved one fit, and nothing is lost.
+        assert_eq!(zs.add(b"c", 3.0), None);
+        assert_eq!(zs.add(b"a", 3.0), None);
+        assert_eq!(zs.as_listpack().as_bytes(), &before[..]);
+        assert_eq!(zs.score(b"a"), Some(1.0));
+        assert_eq!(tracker.rejected(), 2);
+
+        tracker.set_budget(None);
+        assert_eq!(zs.add(b"a", 3.0), Some(false));
+        assert_eq!(members(zs.range_by_rank(0, -1)), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn range_by_score() {
+        let mut zs = ListpackSortedSet::new();
+        for (i, m) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            zs.add(m.as_bytes(), i as f64);
+        }
+
+        let range = zs.range_by_score(Bound::Included(1.0), Bound::Included(3.0));
+        assert_eq!(members(range), vec!["b", "c", "d"]);
+        let range = zs.range_by_score(Bound::Excluded(1.0), Bound::Excluded(3.0));
+        assert_eq!(members(range), vec!["c"]);
+        let range = zs.range_by_score(Bound::Unbounded, Bound::Excluded(2.0));
+        assert_eq!(members(range), vec!["a", "b"]);
+        let range = zs.range_by_score(Bound::Excluded(3.0), Bound::Unbounded);
+        assert_eq!(members(range), vec!["e"]);
+    }
+
+    #[test]
+    fn range_by_lex() {
+        let mut zs = ListpackSortedSet::new();
+        for m in ["d", "a", "c", "b", "e"].iter() {
+            zs.add(m.as_bytes(), 0.0);
+        }
+
+        let range = zs.range_by_lex(Bound::Included(b"b"), Bound::Excluded(b"d"));
+        assert_eq!(members(range), vec!["b", "c"]);
+        let range = zs.range_by_lex(Bound::Excluded(b"b"), Bound::Unbounded);
+        assert_eq!(members(range), vec!["c", "d", "e"]);
+        let range = zs.range_by_lex(Bound::Unbounded, Bound::Included(b"a"));
+        assert_eq!(members(range), vec!["a"]);
+    }
+
+    #[test]
+    fn score_encoding() {
+        let mut zs = ListpackSortedSet::new();
+        zs.add(b"int", 2.0);
+        zs.add(b"float", 2.5);
+
+        let lp = zs.as_listpack().0;
+        assert_eq!(get_str(last(lp).unwrap()), "2.5");
+        match get(next(lp, first(lp).unwrap()).unwrap()) {
+            Value::Int(v) => assert_eq!(v, 2),
+            _ => panic!("integral scores should be integer encoded")
+        }
+    }
+
+    #[test]
+    fn score_format() {
+        // What Redis 7.2 d2string() writes for the same doubles.
+        let cases: &[(f64, &str)] = &[
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (3.0, "3"),
+            (-17.0, "-17"),
+            (1.5, "1.5"),
+            (-2.5, "-2.5"),
+            (0.1, "0.1"),
+            (1.0 / 3.0, "0.3333333333333333"),
+            (123456.789, "123456.789"),
+            (0.0001, "0.0001"),
+            (0.00012, "0.00012"),
+            (1e-5, "1e-05"),
+            (-1.25e-7, "-1.25e-07"),
+            (1e-300, "1e-300"),
+            (1e17, "100000000000000000"),
+            (4611686018427387904.0, "4611686018427387904"),
+            (-4611686018427387904.0, "-4611686018427387904"),
+            (9223372036854775808.0, "9.223372036854776e+18"),
+            (1e21, "1e+21"),
+            (1e300, "1e+300"),
+            (-1.7976931348623157e308, "-1.7976931348623157e+308"),
+            (5e-324, "5e-324"),
+            (std::f64::INFINITY, "inf"),
+            (std::f64::NEG_INFINITY, "-inf"),
+            (std::f64::NAN, "nan"),
+        ];
+        for &(score, expected) in cases {
+            assert_eq!(score_to_string(score), expected);
+        }
+    }
+}